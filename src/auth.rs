@@ -0,0 +1,183 @@
+use std::{
+    env::VarError,
+    fmt,
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+
+use bevy_renet::renet::{ConnectToken, TokenGenerationError, NETCODE_KEY_BYTES, NETCODE_USER_DATA_BYTES};
+
+use crate::PROTOCOL_ID;
+
+/// Environment variable ServerAuth::from_env reads the hex encoded private key from
+pub const PRIVATE_KEY_ENV: &str = "PUNCHTHROUGH_PRIVATE_KEY";
+
+/// How the rendezvous server authenticates connecting clients
+#[derive(Clone)]
+pub enum ServerAuth {
+    /// Anyone who knows the protocol id can connect. Fine for local testing only
+    Unsecure,
+    /// Clients need a ConnectToken signed with this key, see TokenIssuer
+    Secure { private_key: [u8; NETCODE_KEY_BYTES] },
+}
+
+impl ServerAuth {
+    /// Secure mode if PUNCHTHROUGH_PRIVATE_KEY is set, unsecure mode if it isn't. A key that is set but unreadable
+    /// is an error, never a reason to fall back to unsecure mode
+    pub fn from_env() -> Result<Self, KeyError> {
+        match std::env::var(PRIVATE_KEY_ENV) {
+            Ok(hex) => Ok(Self::Secure {
+                private_key: parse_private_key(&hex)?,
+            }),
+            Err(VarError::NotPresent) => Ok(Self::Unsecure),
+            Err(VarError::NotUnicode(_)) => Err(KeyError::NotHex),
+        }
+    }
+}
+
+impl fmt::Debug for ServerAuth {
+    /// Never prints the key itself
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unsecure => write!(f, "Unsecure"),
+            Self::Secure { .. } => write!(f, "Secure"),
+        }
+    }
+}
+
+/// How a client authenticates with the rendezvous server. Must match the server's ServerAuth
+#[derive(Debug, Clone)]
+pub enum ClientAuth {
+    Unsecure,
+    /// A token from the game backend's TokenIssuer
+    Secure { connect_token: ConnectToken },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyError {
+    /// The key must be exactly NETCODE_KEY_BYTES bytes, hex encoded
+    WrongLength { hex_chars: usize },
+    NotHex,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WrongLength { hex_chars } => write!(
+                f,
+                "private key must be {} hex characters but was {hex_chars}",
+                NETCODE_KEY_BYTES * 2
+            ),
+            Self::NotHex => write!(f, "private key must only contain hex characters"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// Parses a hex encoded private key, as produced by private_key_to_hex
+pub fn parse_private_key(hex: &str) -> Result<[u8; NETCODE_KEY_BYTES], KeyError> {
+    let hex = hex.trim();
+    if hex.len() != NETCODE_KEY_BYTES * 2 {
+        return Err(KeyError::WrongLength { hex_chars: hex.len() });
+    }
+    //from_str_radix would also take a sign, "+f" is not a byte of any key
+    if !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(KeyError::NotHex);
+    }
+
+    let mut key = [0u8; NETCODE_KEY_BYTES];
+    for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| KeyError::NotHex)?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| KeyError::NotHex)?;
+    }
+
+    Ok(key)
+}
+
+pub fn private_key_to_hex(key: &[u8; NETCODE_KEY_BYTES]) -> String {
+    key.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// A fresh random key for a new deployment
+pub fn generate_private_key() -> [u8; NETCODE_KEY_BYTES] {
+    rand::random()
+}
+
+/// Issues ConnectTokens for a secure rendezvous server. This is meant to live in the game backend, which hands
+/// tokens out to players it has authenticated and keeps the private key away from game clients
+pub struct TokenIssuer {
+    pub private_key: [u8; NETCODE_KEY_BYTES],
+    pub protocol_id: u64,
+    /// Public addresses of the rendezvous servers the token is valid for
+    pub server_addresses: Vec<SocketAddr>,
+    /// How long a token can be used to start a connection
+    pub expire_seconds: u64,
+    /// How long a connection made with the token survives without hearing from the other side
+    pub timeout_seconds: i32,
+}
+
+impl TokenIssuer {
+    pub fn new(private_key: [u8; NETCODE_KEY_BYTES], server_addresses: Vec<SocketAddr>) -> Self {
+        Self {
+            private_key,
+            protocol_id: PROTOCOL_ID,
+            server_addresses,
+            expire_seconds: 300,
+            timeout_seconds: 15,
+        }
+    }
+
+    pub fn issue(
+        &self,
+        client_id: u64,
+        user_data: Option<&[u8; NETCODE_USER_DATA_BYTES]>,
+    ) -> Result<ConnectToken, TokenGenerationError> {
+        let current_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO);
+
+        ConnectToken::generate(
+            current_time,
+            self.protocol_id,
+            self.expire_seconds,
+            client_id,
+            self.timeout_seconds,
+            self.server_addresses.clone(),
+            user_data,
+            &self.private_key,
+        )
+    }
+
+    /// Issues a token already serialized for sending to a game client, which reads it back with ConnectToken::read
+    pub fn issue_bytes(&self, client_id: u64) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let token = self.issue(client_id, None)?;
+        let mut bytes = Vec::new();
+        token.write(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_keys_round_trip_through_hex() {
+        let key = generate_private_key();
+        let hex = private_key_to_hex(&key);
+
+        assert_eq!(hex.len(), NETCODE_KEY_BYTES * 2);
+        assert_eq!(parse_private_key(&hex), Ok(key));
+        assert_eq!(parse_private_key(&hex.to_uppercase()), Ok(key));
+    }
+
+    #[test]
+    fn malformed_keys_are_rejected() {
+        assert_eq!(parse_private_key("abcd"), Err(KeyError::WrongLength { hex_chars: 4 }));
+        assert_eq!(parse_private_key(&"zz".repeat(NETCODE_KEY_BYTES)), Err(KeyError::NotHex));
+        assert_eq!(parse_private_key(&"é".repeat(NETCODE_KEY_BYTES)), Err(KeyError::NotHex));
+        assert_eq!(parse_private_key(&"+f".repeat(NETCODE_KEY_BYTES)), Err(KeyError::NotHex));
+        assert_eq!(parse_private_key(&"-0".repeat(NETCODE_KEY_BYTES)), Err(KeyError::NotHex));
+    }
+}
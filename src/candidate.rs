@@ -0,0 +1,336 @@
+use std::{
+    cmp::Reverse,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::nat::{is_global_v6, IpFamily};
+
+/// Where an address a peer may be reached on came from
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CandidateKind {
+    /// An address on the peer's own network interface
+    Host,
+    /// The peer's public address as the rendezvous server sees it
+    ServerReflexive,
+    /// An address the peer's NAT is predicted to hand out, see ClientMapping::predicted_addrs
+    Predicted,
+    /// The rendezvous server's relay socket. Never probed, it is requested once every other pair failed
+    Relay,
+}
+
+impl CandidateKind {
+    /// Direct paths are preferred over ones through the NAT, and anything over the relay
+    pub fn type_preference(self) -> u32 {
+        match self {
+            Self::Host => 126,
+            Self::ServerReflexive => 100,
+            Self::Predicted => 90,
+            Self::Relay => 0,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Candidate {
+    pub kind: CandidateKind,
+    pub addr: SocketAddr,
+    pub priority: u32,
+}
+
+impl Candidate {
+    /// local_preference orders candidates of the same kind, higher is tried first
+    pub fn new(kind: CandidateKind, addr: SocketAddr, local_preference: u16) -> Self {
+        Self {
+            kind,
+            addr,
+            priority: (kind.type_preference() << 24) + ((local_preference as u32) << 8) + 255,
+        }
+    }
+}
+
+/// Orders candidates of the same kind: global IPv6 addresses first, since there is usually no NAT in the way,
+/// then by the order they were gathered in
+pub fn local_preference(index: usize, addr: SocketAddr) -> u16 {
+    let family = match is_global_v6(addr.ip()) {
+        true => 0x8000,
+        false => 0,
+    };
+    family | 0x7fff_u16.saturating_sub(u16::try_from(index).unwrap_or(u16::MAX))
+}
+
+/// Everything a peer can be reached on, most preferred first. Each address is only listed once, under the first
+/// kind it was gathered as
+pub fn gather(
+    host: &[SocketAddr],
+    reflexive: Option<SocketAddr>,
+    predicted: &[SocketAddr],
+    relay: Option<SocketAddr>,
+) -> Vec<Candidate> {
+    let addrs = host
+        .iter()
+        .map(|addr| (CandidateKind::Host, *addr))
+        .chain(reflexive.map(|addr| (CandidateKind::ServerReflexive, addr)))
+        .chain(predicted.iter().map(|addr| (CandidateKind::Predicted, *addr)))
+        .chain(relay.map(|addr| (CandidateKind::Relay, addr)));
+
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for (index, (kind, addr)) in addrs.enumerate() {
+        if candidates.iter().any(|candidate| candidate.addr == addr) {
+            continue;
+        }
+        candidates.push(Candidate::new(kind, addr, local_preference(index, addr)));
+    }
+    candidates
+}
+
+/// The controlling side picks which pair both sides use, the controlled side goes along with it.
+/// The server makes the host controlling and the joiner controlled
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Controlling,
+    Controlled,
+}
+
+/// Orders pairs the same way on both sides, see RFC 8445 section 6.1.2.3
+pub fn pair_priority(controlling: u32, controlled: u32) -> u64 {
+    let (min, max) = (controlling.min(controlled) as u64, controlling.max(controlled) as u64);
+    (min << 32) + 2 * max + (controlling > controlled) as u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairState {
+    /// Not probed yet
+    Waiting,
+    /// Probed, no answer yet
+    InProgress,
+    /// The peer acknowledged a probe sent to it, the pair is valid
+    Succeeded,
+}
+
+/// One of our candidates and one of the peer's. Every probe leaves from the same socket, local only decides the
+/// pair's priority
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CandidatePair {
+    pub local: Candidate,
+    pub remote: Candidate,
+    pub priority: u64,
+    pub state: PairState,
+}
+
+/// The pairs to check for one peer, highest priority first
+#[derive(Debug, Clone)]
+pub struct Checklist {
+    pub role: Role,
+    /// Settles which side is controlling when both think they are, or neither does
+    pub tie_breaker: u64,
+    pub local: Vec<Candidate>,
+    pub pairs: Vec<CandidatePair>,
+    /// The peer's relay candidate, used once every pair failed
+    pub relay: Option<Candidate>,
+}
+
+impl Checklist {
+    pub fn new(role: Role, tie_breaker: u64, local: &[Candidate], remote: &[Candidate]) -> Self {
+        let mut checklist = Self {
+            role,
+            tie_breaker,
+            local: local.to_vec(),
+            pairs: Vec::new(),
+            relay: None,
+        };
+        for candidate in remote {
+            checklist.add_remote(*candidate);
+        }
+        checklist
+    }
+
+    /// Pairs a candidate of the peer with ours of the same family. Host candidates pair with our host candidate, the
+    /// rest with our public one. Dropped if we have candidates but none of its family, we couldn't reach it
+    pub fn add_remote(&mut self, remote: Candidate) {
+        if remote.kind == CandidateKind::Relay {
+            self.relay = Some(remote);
+            return;
+        }
+        if self.contains(remote.addr) {
+            return;
+        }
+
+        let preferred = match remote.kind {
+            CandidateKind::Host => CandidateKind::Host,
+            _ => CandidateKind::ServerReflexive,
+        };
+        let family = IpFamily::of(remote.addr);
+        let reachable: Vec<&Candidate> = self
+            .local
+            .iter()
+            .filter(|candidate| candidate.kind != CandidateKind::Relay && IpFamily::of(candidate.addr) == family)
+            .collect();
+        let local = match reachable.iter().find(|candidate| candidate.kind == preferred).or_else(|| reachable.first()) {
+            Some(local) => **local,
+            None if self.local.is_empty() => {
+                Candidate::new(CandidateKind::ServerReflexive, unspecified(remote.addr), u16::MAX)
+            }
+            None => return,
+        };
+
+        self.pairs.push(CandidatePair {
+            local,
+            remote,
+            priority: 0,
+            state: PairState::Waiting,
+        });
+        self.prioritize();
+    }
+
+    pub fn contains(&self, addr: SocketAddr) -> bool {
+        self.pairs.iter().any(|pair| pair.remote.addr == addr)
+    }
+
+    /// The peer's addresses in the order they are checked
+    pub fn remote_addrs(&self) -> Vec<SocketAddr> {
+        self.pairs.iter().map(|pair| pair.remote.addr).collect()
+    }
+
+    /// Records that every waiting pair was just probed
+    pub fn on_checks_sent(&mut self) {
+        for pair in self.pairs.iter_mut().filter(|pair| pair.state == PairState::Waiting) {
+            pair.state = PairState::InProgress;
+        }
+    }
+
+    pub fn on_success(&mut self, remote: SocketAddr) {
+        if let Some(pair) = self.pairs.iter_mut().find(|pair| pair.remote.addr == remote) {
+            pair.state = PairState::Succeeded;
+        }
+    }
+
+    /// The highest priority pair the peer answered on
+    pub fn best_valid(&self) -> Option<&CandidatePair> {
+        self.pairs.iter().find(|pair| pair.state == PairState::Succeeded)
+    }
+
+    /// Called with the role and tie breaker from a peer's probe. When both sides claim the same role the larger tie
+    /// breaker is controlling, both sides work that out on their own. Returns true if our role changed
+    pub fn on_role_conflict(&mut self, remote_role: Role, remote_tie_breaker: u64) -> bool {
+        if remote_role != self.role {
+            return false;
+        }
+
+        let role = match self.tie_breaker >= remote_tie_breaker {
+            true => Role::Controlling,
+            false => Role::Controlled,
+        };
+        if role == self.role {
+            return false;
+        }
+        self.role = role;
+        self.prioritize();
+        true
+    }
+
+    fn prioritize(&mut self) {
+        let role = self.role;
+        for pair in self.pairs.iter_mut() {
+            pair.priority = match role {
+                Role::Controlling => pair_priority(pair.local.priority, pair.remote.priority),
+                Role::Controlled => pair_priority(pair.remote.priority, pair.local.priority),
+            };
+        }
+        self.pairs.sort_by_key(|pair| Reverse(pair.priority));
+    }
+}
+
+/// Stands in for our own address when we have no candidates to pair with
+fn unspecified(like: SocketAddr) -> SocketAddr {
+    let ip: IpAddr = match like {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    SocketAddr::new(ip, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn candidates_are_gathered_by_preference() {
+        let public = addr("203.0.113.7:40000");
+        let candidates = gather(
+            &[addr("192.168.1.20:40000"), public],
+            Some(public),
+            &[addr("203.0.113.7:40001")],
+            Some(addr("198.51.100.1:5002")),
+        );
+
+        let kinds: Vec<CandidateKind> = candidates.iter().map(|candidate| candidate.kind).collect();
+        use CandidateKind::*;
+        assert_eq!(kinds, [Host, Host, Predicted, Relay]);
+        assert!(candidates.windows(2).all(|pair| pair[0].priority > pair[1].priority));
+    }
+
+    #[test]
+    fn both_sides_order_pairs_the_same_way() {
+        let host = gather(&[addr("192.168.1.20:40000")], Some(addr("203.0.113.7:40000")), &[], None);
+        let joiner = gather(
+            &[addr("10.0.0.5:50000")],
+            Some(addr("198.51.100.4:50000")),
+            &[addr("198.51.100.4:50001")],
+            Some(addr("198.51.100.1:5002")),
+        );
+
+        let controlling = Checklist::new(Role::Controlling, 1, &host, &joiner);
+        let controlled = Checklist::new(Role::Controlled, 2, &joiner, &host);
+
+        assert_eq!(
+            controlling.remote_addrs(),
+            [addr("10.0.0.5:50000"), addr("198.51.100.4:50000"), addr("198.51.100.4:50001")]
+        );
+        assert_eq!(controlling.relay.map(|relay| relay.addr), Some(addr("198.51.100.1:5002")));
+        assert_eq!(controlled.remote_addrs(), [addr("192.168.1.20:40000"), addr("203.0.113.7:40000")]);
+        assert_eq!(controlling.pairs[0].priority, controlled.pairs[0].priority);
+    }
+
+    #[test]
+    fn larger_tie_breaker_takes_control() {
+        let remote = gather(&[], Some(addr("203.0.113.7:40000")), &[], None);
+        let mut ours = Checklist::new(Role::Controlling, 10, &[], &remote);
+        let mut theirs = Checklist::new(Role::Controlling, 20, &[], &remote);
+
+        assert!(ours.on_role_conflict(Role::Controlling, 20));
+        assert!(!theirs.on_role_conflict(Role::Controlling, 10));
+        assert_eq!((ours.role, theirs.role), (Role::Controlled, Role::Controlling));
+        assert!(!ours.on_role_conflict(Role::Controlling, 20));
+    }
+
+    #[test]
+    fn global_ipv6_pairs_are_checked_first() {
+        let host = gather(
+            &[addr("192.168.1.20:40000"), addr("[2606:4700::20]:40000")],
+            Some(addr("203.0.113.7:40000")),
+            &[],
+            None,
+        );
+        let joiner = gather(
+            &[addr("[fd00::5]:50000"), addr("[2a00:1450::5]:50000")],
+            Some(addr("198.51.100.4:50000")),
+            &[],
+            None,
+        );
+        let v4_only = gather(&[addr("10.0.0.5:50000")], Some(addr("198.51.100.4:50000")), &[], None);
+
+        let controlling = Checklist::new(Role::Controlling, 1, &host, &joiner);
+        assert_eq!(controlling.pairs[0].remote.addr, addr("[2a00:1450::5]:50000"));
+        assert_eq!(controlling.pairs[0].local.addr, addr("[2606:4700::20]:40000"));
+        assert!(controlling.pairs.iter().all(|pair| IpFamily::of(pair.local.addr) == IpFamily::of(pair.remote.addr)));
+
+        let controlled = Checklist::new(Role::Controlled, 2, &v4_only, &host);
+        assert_eq!(controlled.remote_addrs(), [addr("192.168.1.20:40000"), addr("203.0.113.7:40000")]);
+    }
+}
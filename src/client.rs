@@ -1,114 +1,923 @@
-use bevy::prelude::*;
-use bevy_renet::renet::{ClientAuthentication, RenetClient, RenetConnectionConfig};
+use bevy::{ecs::event::Events, prelude::*};
+use bevy_renet::renet::{ConnectToken, RenetConnectionConfig, NETCODE_KEY_BYTES};
 use std::{
+    collections::{HashMap, HashSet},
+    io::{self, ErrorKind},
     net::{SocketAddr, UdpSocket},
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 
-use crate::{ClientChannel, ClientHostMessage, ServerChannel, PROTOCOL_ID, ClientError};
+use crate::{
+    auth::ClientAuth,
+    lobby::{LobbyFilter, LobbyInfo, LobbyListing, LobbyPage},
+    nat::{canonical, local_candidates, sendable, ConnectStrategy, IpFamily, NatProbe, NatType},
+    punch::{KeepaliveSchedule, PeerLink, PunchAttempt, PunchPacket, PunchSchedule, TimeoutCause, MAX_DATAGRAM_SIZE},
+    renet_plugin::{has_resource, PTRenetClientPlugin, PTRenetSystem},
+    protocol::{AddressCheck, Capabilities, ClientToServer, RequestId, ServerToClient},
+    transport::RendezvousTransport,
+    ClientChannel, ClientError, ClientMessage, ClientMessaging, Hello, HelloResponse, ServerChannel,
+};
+/// How many copies of each mapping probe are sent
+const MAPPING_PROBE_COPIES: usize = 3;
+
 pub struct PunchthroughClientPlugin {
     pub local_socket: SocketAddr,
     pub punchthrough_server: SocketAddr,
+    /// Must match how the rendezvous server is configured. Secure servers need a ConnectToken from the game backend
+    pub authentication: ClientAuth,
+    /// Must match the rendezvous server's protocol id. Only used with ClientAuth::Unsecure, a ConnectToken carries its own
+    pub protocol_id: u64,
+    /// How persistently each peer is probed before the handshake is given up on
+    pub punch_schedule: PunchSchedule,
+    pub keepalive: KeepaliveSchedule,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum RequestSwap {
     JoinLobby {lobby: String},
-    HostLobby
+    /// info is what joiners and the lobby list see, max_players caps how many can join
+    HostLobby {info: LobbyInfo},
+    /// Replaces the info of the lobby we host, e.g. when players come and go or the map changes
+    UpdateLobby {info: LobbyInfo},
+}
+
+/// Asks the rendezvous server for a page of its public lobbies, answered with LobbyListReceived or LobbyListFailed
+#[derive(Debug, Clone, Default)]
+pub struct RequestLobbyList {
+    pub filter: LobbyFilter,
+    pub page: LobbyPage,
+}
+
+/// A page of public lobbies, for a server browser. total counts every lobby matching the filter
+#[derive(Debug, Clone)]
+pub struct LobbyListReceived {
+    pub lobbies: Vec<LobbyListing>,
+    pub page: LobbyPage,
+    pub total: u32,
+}
+
+/// The server refused a RequestLobbyList. Hosting and joining carry on unaffected
+#[derive(Debug, Clone)]
+pub struct LobbyListFailed {
+    pub err: ClientError,
 }
 
 /// This is the egress point of the plugin. Client apps should listen for this event
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum PunchthroughEvent {
-    Success {target_sock: SocketAddr, local_sock: SocketAddr},
+    /// family is the IP version the punched path uses, IPv6 is preferred when both peers have a global address
+    Success {target_sock: SocketAddr, local_sock: SocketAddr, family: IpFamily},
     HostSuccess {lobby: String},
-    Failed {reason: String}
+    /// The server matched us with the host of lobby, info is its metadata at the time we joined
+    JoinSuccess {lobby: String, info: LobbyInfo},
+    /// The server accepted our RequestSwap::UpdateLobby
+    LobbyUpdated {lobby: String},
+    /// A client joined our hosted lobby, a Success or Failed for the punch towards it will follow
+    PeerJoining {lobby: String, peer_id: u64},
+    /// The host of a lobby we were joining went away
+    LobbyClosed {lobby: String},
+    /// Message of the day from the rendezvous server's operator
+    Motd {message: String},
+    /// Our NAT was probed, the result is also kept in the NatType resource
+    NatClassified {nat_type: NatType},
+    /// Punching failed but the rendezvous server relays datagrams to the peer instead. Slower, but working.
+    /// Exchange datagrams with PunchthroughClientRes::send_relayed and take_relayed
+    Relayed {relay: SocketAddr, peer: SocketAddr},
+    /// A punched peer sent no keepalive for KeepaliveSchedule::peer_timeout, its mapping or ours probably died.
+    /// PunchthroughClientRes::peer_link keeps counting in case it comes back
+    PeerUnresponsive {peer: SocketAddr, keepalives_sent: u64, keepalives_received: u64},
+    /// Our public address moved, because we rebound or because the server noticed a NAT reboot or network handover,
+    /// and the rendezvous server carried our session over to current. A lobby we host now points joiners there
+    AddressChanged {previous: SocketAddr, current: SocketAddr},
+    Failed {reason: FailureReason}
+}
+
+/// Where the client is in the rendezvous and punch process, kept as a resource so UI can show connection status
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PunchthroughState {
+    /// The connection to the rendezvous server was lost
+    Disconnected,
+    /// Connecting to the rendezvous server and agreeing on a protocol version
+    ConnectingToRendezvous,
+    /// Connected to the rendezvous server, not hosting or joining anything
+    Idle,
+    /// Our lobby is open and waiting for someone to join
+    Hosting { lobby: String },
+    /// Waiting for the server to introduce us to the host of a lobby
+    Joining { lobby: String },
+    /// Probing a peer, attempts is the number of probes sent so far
+    Punching { peer: SocketAddr, attempts: u32 },
+    /// A peer acknowledged our probes, the path to it is open
+    Connected { peer: SocketAddr },
+    /// Punching failed, datagrams to the peer go through the rendezvous server's relay
+    Relayed { peer: SocketAddr },
+    Failed { reason: FailureReason },
+}
+
+/// Sent whenever PunchthroughState changes
+#[derive(Debug, Clone)]
+pub struct PunchthroughStateChanged {
+    pub previous: PunchthroughState,
+    pub current: PunchthroughState,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FailureReason {
+    /// The server refused the connection or one of our requests
+    Server(ClientError),
+    /// The lobby being joined closed before the punch went through
+    LobbyClosed { lobby: String },
+    /// The peer never acknowledged any of our probes
+    PunchTimeout {
+        target: SocketAddr,
+        probes_sent: u32,
+        cause: TimeoutCause,
+    },
+    /// The relay session ran out of time or the peer left it
+    RelayClosed { peer: SocketAddr },
+    /// The server gave us no address of the peer's to punch towards
+    NoCandidates { peer: SocketAddr },
+}
+
+/// A relay session the server set up for us, see ServerToClient::RelayReady
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayLink {
+    pub relay: SocketAddr,
+    pub token: u64,
+    pub peer: SocketAddr,
 }
 
 pub struct PunchthroughClientRes {
-    /// Contains a tuple of the address received from the server swap response, the number of times its been tried, and the last time it was tried
-    pub client: RenetClient,
-    pub target_addr: Option<(SocketAddr, u16)>,
+    pub client: RendezvousTransport,
+    /// A handle to the same UDP socket the RenetClient talks to the server on.
+    /// Probes have to leave from this socket, it is the one whose public mapping the server reported to our peer
+    pub punch_socket: UdpSocket,
+    /// Punch packets taken off the shared socket this frame, waiting to be processed
+    pub punch_inbox: Vec<(SocketAddr, PunchPacket)>,
+    /// The handshake currently in flight, if the server has told us to attempt one
+    pub punch_attempt: Option<PunchAttempt>,
+    pub punch_schedule: PunchSchedule,
+    pub keepalive: KeepaliveSchedule,
+    /// When we last sent the server a keepalive while hosting
+    pub server_keepalive_at: Option<Duration>,
+    /// The path to the last peer we punched through to, kept open with keepalives
+    pub peer_link: Option<PeerLink>,
+    /// Running while the server's observers echo our mapping back, requests wait for it to finish
+    pub nat_probe: Option<NatProbe>,
+    /// Set once the server relays to our peer in place of a direct path
+    pub relay: Option<RelayLink>,
+    /// The relay request sent after a punch timed out, and the timeout to report if it is refused
+    pub relay_request: Option<(RequestId, FailureReason)>,
+    /// Payloads the peer sent through the relay, waiting for take_relayed
+    pub relay_inbox: Vec<Vec<u8>>,
+    /// Requests sent to the server that haven't been answered yet, so responses can be tied back to what caused them
+    pub pending_requests: HashMap<RequestId, RequestSwap>,
+    pub next_request_id: u32,
+    pub hello_sent: bool,
+    /// Set once the server accepted our Hello, holding the capabilities both sides support
+    pub server_capabilities: Option<Capabilities>,
+    /// Requests made before the server accepted our Hello or while our NAT is being probed, sent as soon as possible
+    pub queued_requests: Vec<RequestSwap>,
+    /// Lobby lists asked for before the server accepted our Hello
+    pub queued_lobby_lists: Vec<RequestLobbyList>,
+    /// Lobby lists asked for that the server hasn't answered yet
+    pub lobby_list_requests: HashSet<RequestId>,
+    /// Answers to our lobby list requests, handed out as LobbyListReceived or LobbyListFailed by lobby_list_system
+    pub lobby_list_inbox: Vec<Result<LobbyListReceived, ClientError>>,
+    /// The lobby the server opened for us, the host serves the game connection after a handoff
+    pub hosting: Option<String>,
     pub local_socket: SocketAddr,
     pub punchthrough_server: SocketAddr,
+    /// Used again when the socket is rebound. A Secure client needs a fresh ConnectToken here before rebinding
+    pub authentication: ClientAuth,
+    pub protocol_id: u64,
+    /// Our public address as the rendezvous server last saw it
+    pub public_addr: Option<SocketAddr>,
+    /// Lets us carry this session over to another socket, see rebind
+    pub resume_token: Option<u64>,
+    /// The session to resume once the server welcomes our rebound socket
+    pub resume: Option<PendingResume>,
+    /// Lets the server notice our public address moving while we host, see AddressCheck
+    pub address_check: Option<AddressCheck>,
+}
+
+/// A session to carry over to our new connection once the server welcomes it, see ClientToServer::Resume
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingResume {
+    pub client_id: u64,
+    pub resume_token: u64,
+    /// The lobby we were hosting, Hosting is taken back up once the server answers
+    pub lobby: Option<String>,
+    /// Set once the Resume request went out
+    pub request: Option<RequestId>,
+}
+
+impl PunchthroughClientRes {
+    /// Sends a request to the server and remembers which RequestSwap it was made for
+    pub fn send_request(&mut self, body: ClientToServer, origin: RequestSwap) -> RequestId {
+        let request_id = self.send_message(body);
+        self.pending_requests.insert(request_id, origin);
+        request_id
+    }
+
+    fn send_message(&mut self, body: ClientToServer) -> RequestId {
+        let request_id = RequestId(self.next_request_id);
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+
+        self.client.send_to_server(ClientMessage { request_id, body });
+        request_id
+    }
+
+    /// Sends a datagram to the peer through the relay. Fails with NotConnected unless we are relayed
+    pub fn send_relayed(&self, payload: &[u8]) -> io::Result<()> {
+        let link = self.relay.ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "no relay session"))?;
+        let packet = PunchPacket::Relayed {
+            token: link.token,
+            payload: payload.to_vec(),
+        };
+        send_from(&self.punch_socket, &packet.encode(), link.relay)?;
+        Ok(())
+    }
+
+    /// Connects to the server again over socket and asks it to carry our session over, see rebind. Everything tied to
+    /// the old connection is dropped, only a lobby we host survives the move
+    fn reconnect(&mut self, socket: UdpSocket) -> io::Result<()> {
+        let (client, punch_socket) =
+            new_rendezvous_transport(socket, self.punchthrough_server, &self.authentication, self.protocol_id)?;
+        //The old connection is left to time out rather than disconnected, which would close our lobby
+        let client_id = self.client.client_id();
+        let lobby = self.hosting.take();
+        self.resume = self.resume_token.take().map(|resume_token| PendingResume {
+            client_id,
+            resume_token,
+            lobby,
+            request: None,
+        });
+        self.client = client;
+        self.punch_socket = punch_socket;
+        self.hello_sent = false;
+        self.server_capabilities = None;
+        self.server_keepalive_at = None;
+        self.pending_requests.clear();
+        self.lobby_list_requests.clear();
+        self.punch_inbox.clear();
+        self.punch_attempt = None;
+        self.peer_link = None;
+        self.nat_probe = None;
+        self.relay = None;
+        self.relay_request = None;
+        self.relay_inbox.clear();
+        self.address_check = None;
+        Ok(())
+    }
+
+    /// Datagrams the peer sent through the relay since the last call
+    pub fn take_relayed(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.relay_inbox)
+    }
+}
+
+/// Moves the rendezvous client to a new socket bound to local_socket, after a network change left the old one unusable.
+/// The server moves our lobby over to the new socket's public address, answered with PunchthroughEvent::AddressChanged.
+/// A handshake in flight, the link to a punched peer and a relay session are dropped, the peer has to punch towards us
+/// again. Punchthrough is ConnectingToRendezvous until the server welcomes the new socket. A host whose socket is
+/// still usable doesn't need this, the server notices its NAT mapping moving if it has an observer, see AddressCheck
+pub fn rebind(world: &mut World, local_socket: SocketAddr) -> io::Result<()> {
+    let socket = UdpSocket::bind(local_socket)?;
+    let mut client_res = world.resource_mut::<PunchthroughClientRes>();
+    client_res.reconnect(socket)?;
+    client_res.local_socket = local_socket;
+    info!("Rebound to {local_socket}");
+
+    let next = PunchthroughState::ConnectingToRendezvous;
+    let previous = std::mem::replace(&mut *world.resource_mut::<PunchthroughState>(), next.clone());
+    if previous != next {
+        let changed = PunchthroughStateChanged { previous, current: next };
+        world.resource_mut::<Events<PunchthroughStateChanged>>().send(changed);
+    }
+    Ok(())
 }
 
 impl Plugin for PunchthroughClientPlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<RequestSwap>();
+        app.add_event::<RequestLobbyList>();
+        app.add_event::<LobbyListReceived>();
+        app.add_event::<LobbyListFailed>();
         app.add_event::<PunchthroughEvent>();
+        app.add_event::<PunchthroughStateChanged>();
+        app.insert_resource(PunchthroughState::ConnectingToRendezvous);
+        app.insert_resource(NatType::Unknown);
+        app.add_plugin(PTRenetClientPlugin);
+
+        info!("Binding Local Socket to {}", self.local_socket);
+        let (client, punch_socket) = UdpSocket::bind(self.local_socket)
+            .and_then(|socket| {
+                new_rendezvous_transport(socket, self.punchthrough_server, &self.authentication, self.protocol_id)
+            })
+            .unwrap();
         app.insert_resource(PunchthroughClientRes {
-            client: new_renet_client(self),
-            target_addr: None,
+            client,
+            punch_socket,
+            punch_inbox: Vec::new(),
+            punch_attempt: None,
+            punch_schedule: self.punch_schedule,
+            keepalive: self.keepalive,
+            server_keepalive_at: None,
+            peer_link: None,
+            nat_probe: None,
+            relay: None,
+            relay_request: None,
+            relay_inbox: Vec::new(),
+            pending_requests: HashMap::new(),
+            next_request_id: 0,
+            hello_sent: false,
+            server_capabilities: None,
+            queued_requests: Vec::new(),
+            queued_lobby_lists: Vec::new(),
+            lobby_list_requests: HashSet::new(),
+            lobby_list_inbox: Vec::new(),
+            hosting: None,
             local_socket: self.local_socket,
             punchthrough_server: self.punchthrough_server,
+            authentication: self.authentication.clone(),
+            protocol_id: self.protocol_id,
+            public_addr: None,
+            resume_token: None,
+            resume: None,
+            address_check: None,
         });
-        app.add_system(punchthrough_system);
+        //Both stop once the socket is handed off, see handoff::hand_off
+        app.add_system_to_stage(
+            CoreStage::PreUpdate,
+            receive_punch_packets
+                .with_run_criteria(has_resource::<PunchthroughClientRes>)
+                .before(PTRenetSystem::ClientUpdate),
+        );
+        app.add_system(punchthrough_system.with_run_criteria(has_resource::<PunchthroughClientRes>));
+        app.add_system(
+            lobby_list_system
+                .with_run_criteria(has_resource::<PunchthroughClientRes>)
+                .after(punchthrough_system),
+        );
+    }
+}
+
+/// Drains every datagram from the shared socket before the rendezvous connection updates. Datagrams from the
+/// rendezvous server go to the RendezvousTransport, everything else is decoded as a punch packet
+pub fn receive_punch_packets(mut client_res: ResMut<PunchthroughClientRes>) {
+    let client_res = client_res.as_mut();
+    let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        match client_res.punch_socket.recv_from(&mut buffer) {
+            Ok((len, from)) if canonical(from) == canonical(client_res.punchthrough_server) => {
+                client_res.client.process_packet(&mut buffer[..len]);
+            }
+            Ok((len, from)) => {
+                if let Some(packet) = PunchPacket::decode(&buffer[..len]) {
+                    client_res.punch_inbox.push((canonical(from), packet));
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) => {
+                warn!("Could not read from punch socket because {e:#?}");
+                break;
+            }
+        }
     }
 }
 
 /// This is the main system of the punchthrough client. It will process server messages and send any responses back up to the server as required
-/// When it receives a swap comand it will insert the info into the PunchThroughClientRes.target field. It will then attempt a handshake at preset intervals
+/// When it receives a swap comand it will start a PunchAttempt in PunchthroughClientRes.punch_attempt. It will then probe the target at preset intervals
+/// until the target acknowledges a probe (Success) or the attempt times out (Failed)
 pub fn punchthrough_system(
     mut client_connect_request: EventReader<RequestSwap>,
     mut punchthrough_events: EventWriter<PunchthroughEvent>,
     mut client_res: ResMut<PunchthroughClientRes>,
+    mut state: ResMut<PunchthroughState>,
+    mut state_changes: EventWriter<PunchthroughStateChanged>,
+    mut nat_type: ResMut<NatType>,
+    time: Res<Time>,
 ) {
-    while let Some(message) = client_res.client
-        .receive_message(ClientChannel::Command.id())
-    {
-        let server_message: ClientHostMessage = bincode::deserialize(&message).unwrap();
+    let now = time.time_since_startup();
+
+    //An open path to a peer doesn't depend on the rendezvous server, and a failure already says why it ended
+    if let Some(reason) = client_res.client.disconnected() {
+        if !matches!(*state, PunchthroughState::Connected { .. } | PunchthroughState::Failed { .. }) {
+            warn!("Lost connection to the rendezvous server: {reason}");
+            transition(&mut state, &mut state_changes, PunchthroughState::Disconnected);
+        }
+    }
+
+    if !client_res.hello_sent && client_res.client.is_connected() {
+        client_res.client.send_hello(Hello::current());
+        client_res.hello_sent = true;
+    }
+
+    if client_res.hello_sent && client_res.server_capabilities.is_none() {
+        receive_hello_response(
+            client_res.as_mut(),
+            &mut punchthrough_events,
+            &mut state,
+            &mut state_changes,
+            *nat_type,
+        );
+    }
+
+    while client_res.server_capabilities.is_some() {
+        let message = match client_res.client.receive_from_server() {
+            Some(message) => message,
+            None => break,
+        };
+        let server_message = match message {
+            Ok(server_message) => server_message,
+            Err(e) => {
+                error!("Error receiving server message {e:#?}");
+                continue;
+            }
+        };
         info!("Client received message from server: {server_message:#?}");
-        match server_message {
-            ClientHostMessage::JoinLobbyResponse { err } => {
-                match err {
-                        Some(ClientError::LobbyNotFound { lobby }) => {
-                            warn!("Lobby not found: {}", lobby)
-                        }
-                        Some(ClientError::InternalServerError) => {
-                            warn!("Received ISE in Join Response")
-                        },
-                        None => {info!("Successfully Swapped")}
-                };
+        let request_id = server_message.request_id;
+        match server_message.body {
+            ServerToClient::JoinLobbyResponse { lobby_id, strategy, info } => {
+                if let Some(id) = request_id {
+                    client_res.pending_requests.remove(&id);
+                }
+                info!("Successfully Swapped into lobby {lobby_id}");
+                match strategy {
+                    ConnectStrategy::Relay => warn!("Our NAT and the host's are unlikely to punch through"),
+                    _ => info!("Connecting to the host with strategy {strategy:?}"),
+                }
+                if matches!(*state, PunchthroughState::Joining { .. }) {
+                    transition(&mut state, &mut state_changes, PunchthroughState::Joining { lobby: lobby_id.clone() });
+                }
+                punchthrough_events.send(PunchthroughEvent::JoinSuccess { lobby: lobby_id, info });
             }
-            
-            ClientHostMessage::AttemptHandshakeCommand { socket } => {
-                //Theoretically you should only have to send one packet to punchthrough
-                match send_pt_packet(client_res.local_socket, socket){
-                    Ok(()) => {
-                        info!("Successfully sent Punchthrough Packet")
-                    },
-                    Err(e) => {
-                        error!("Could not send punchthrough packet to socket {} because {e:#?}", socket.ip());
-                    }
-                };
+            ServerToClient::AttemptHandshakeCommand { peer, role, local, remote } => {
+                info!("Starting punchthrough handshake with {peer} as {role:?}, checking {} candidates", remote.len());
+                let schedule = client_res.punch_schedule;
+                client_res.punch_attempt = PunchAttempt::from_candidates(peer, role, &local, &remote, now, schedule);
+                if client_res.punch_attempt.is_some() {
+                    let punching = PunchthroughState::Punching { peer, attempts: 0 };
+                    transition(&mut state, &mut state_changes, punching);
+                } else {
+                    warn!("Not punching to {peer}, the server sent no candidates for it");
+                    client_res.send_message(ClientToServer::LeaveLobby);
+                    let reason = FailureReason::NoCandidates { peer };
+                    transition(&mut state, &mut state_changes, PunchthroughState::Failed { reason: reason.clone() });
+                    punchthrough_events.send(PunchthroughEvent::Failed { reason });
+                }
+            }
+            ServerToClient::PeerJoining { lobby_id, peer_id } => {
+                punchthrough_events.send(PunchthroughEvent::PeerJoining { lobby: lobby_id, peer_id });
             }
-            ClientHostMessage::NewLobbyResponse{lobby_id} => {
+            ServerToClient::LobbyClosed { lobby_id } => {
+                info!("Lobby {lobby_id} was closed by its host");
+                //Only a handshake that hasn't gone through yet is abandoned, an established link doesn't need the host's lobby
+                let mut failed = false;
+                if matches!(&client_res.punch_attempt, Some(attempt) if !attempt.confirmed) {
+                    client_res.punch_attempt = None;
+                    failed = true;
+                }
+                let reason = FailureReason::LobbyClosed { lobby: lobby_id.clone() };
+                if matches!(*state, PunchthroughState::Joining { .. } | PunchthroughState::Punching { .. }) {
+                    let failed_state = PunchthroughState::Failed { reason: reason.clone() };
+                    transition(&mut state, &mut state_changes, failed_state);
+                    failed = true;
+                }
+                if failed {
+                    punchthrough_events.send(PunchthroughEvent::Failed { reason });
+                }
+                punchthrough_events.send(PunchthroughEvent::LobbyClosed { lobby: lobby_id });
+            }
+            ServerToClient::NewLobbyResponse { lobby_id } => {
+                if let Some(id) = request_id {
+                    client_res.pending_requests.remove(&id);
+                }
+                client_res.hosting = Some(lobby_id.clone());
+                client_res.server_keepalive_at = Some(now);
+                let hosting = PunchthroughState::Hosting { lobby: lobby_id.clone() };
+                transition(&mut state, &mut state_changes, hosting);
                 punchthrough_events.send(PunchthroughEvent::HostSuccess { lobby: lobby_id });
-            },
-            _ => {}
+            }
+            ServerToClient::ObserveMapping { token, observers } => {
+                send_mapping_probes(client_res.as_mut(), token, &observers);
+                //Observers echo the address the probe came from, which is only Open if it matches the interface
+                //that routes to the server rather than the unspecified address the socket may be bound to
+                let bound = client_res.punch_socket.local_addr().unwrap();
+                let server = client_res.punchthrough_server;
+                let local_addr = local_candidates(bound, server)
+                    .into_iter()
+                    .find(|addr| IpFamily::of(*addr) == IpFamily::of(server))
+                    .unwrap_or(bound);
+                client_res.nat_probe = Some(NatProbe::new(token, local_addr, observers, now));
+            }
+            ServerToClient::Motd { message } => {
+                info!("Rendezvous server says: {message}");
+                punchthrough_events.send(PunchthroughEvent::Motd { message });
+            }
+            ServerToClient::RelayReady { relay, token, peer } => {
+                info!("Relaying to {peer} through {relay}");
+                //Whichever side asked first, both give up punching and use the relay
+                client_res.punch_attempt = None;
+                client_res.relay_request = None;
+                client_res.relay = Some(RelayLink { relay, token, peer });
+                client_res.peer_link = None;
+                if let Err(e) = client_res.send_relayed(&[]) {
+                    warn!("Could not register with the relay at {relay} because {e:#?}");
+                }
+                transition(&mut state, &mut state_changes, PunchthroughState::Relayed { peer });
+                punchthrough_events.send(PunchthroughEvent::Relayed { relay, peer });
+            }
+            ServerToClient::LobbyUpdated { lobby_id } => {
+                if let Some(id) = request_id {
+                    client_res.pending_requests.remove(&id);
+                }
+                info!("Updated the info of lobby {lobby_id}");
+                punchthrough_events.send(PunchthroughEvent::LobbyUpdated { lobby: lobby_id });
+            }
+            ServerToClient::LobbyList { lobbies, page, total } => {
+                if let Some(id) = request_id {
+                    client_res.lobby_list_requests.remove(&id);
+                }
+                info!("Received {} of {total} public lobbies", lobbies.len());
+                client_res.lobby_list_inbox.push(Ok(LobbyListReceived { lobbies, page, total }));
+            }
+            ServerToClient::Session { observed, resume_token, address_check } => {
+                info!("Rendezvous server sees us as {observed}");
+                client_res.public_addr = Some(observed);
+                client_res.resume_token = Some(resume_token);
+                client_res.address_check = address_check;
+            }
+            ServerToClient::AddressChanged { previous, observed } => {
+                info!("Our public address moved from {previous} to {observed}");
+                client_res.public_addr = Some(observed);
+                //The lobby we host came along with the resumed session
+                if let Some(lobby) = client_res.resume.take().and_then(|resume| resume.lobby) {
+                    client_res.hosting = Some(lobby.clone());
+                    client_res.server_keepalive_at = Some(now);
+                    transition(&mut state, &mut state_changes, PunchthroughState::Hosting { lobby });
+                }
+                punchthrough_events.send(PunchthroughEvent::AddressChanged { previous, current: observed });
+            }
+            ServerToClient::RelayClosed { token } => {
+                let link = match client_res.relay {
+                    Some(link) if link.token == token => link,
+                    _ => continue,
+                };
+                warn!("Relay to {} was closed", link.peer);
+                client_res.relay = None;
+                let reason = FailureReason::RelayClosed { peer: link.peer };
+                transition(&mut state, &mut state_changes, PunchthroughState::Failed { reason: reason.clone() });
+                punchthrough_events.send(PunchthroughEvent::Failed { reason });
+            }
+            ServerToClient::Error { err } if is_relay_response(&client_res, request_id) => {
+                let (_, reason) = client_res.relay_request.take().expect("Checked by is_relay_response");
+                warn!("Server could not relay after the punch failed: {err:?}");
+                client_res.send_message(ClientToServer::LeaveLobby);
+                transition(&mut state, &mut state_changes, PunchthroughState::Failed { reason: reason.clone() });
+                punchthrough_events.send(PunchthroughEvent::Failed { reason });
+            }
+            ServerToClient::Error { err } if is_resume_response(&client_res, request_id) => {
+                //We are connected again, only the old session and any lobby it hosted are gone
+                let resume = client_res.resume.take().expect("Checked by is_resume_response");
+                warn!("Could not resume session {}: {err:?}", resume.client_id);
+                if let Some(lobby) = resume.lobby {
+                    punchthrough_events.send(PunchthroughEvent::LobbyClosed { lobby });
+                }
+                punchthrough_events.send(PunchthroughEvent::Failed {
+                    reason: FailureReason::Server(err),
+                });
+            }
+            ServerToClient::Error { err } if is_lobby_list_response(&client_res, request_id) => {
+                //Not being able to list lobbies doesn't get in the way of hosting or joining one
+                client_res.lobby_list_requests.remove(&request_id.expect("Checked by is_lobby_list_response"));
+                warn!("Could not list lobbies: {err:?}");
+                client_res.lobby_list_inbox.push(Err(err));
+            }
+            ServerToClient::Error { err } => {
+                match request_id.and_then(|id| client_res.pending_requests.remove(&id)) {
+                    Some(RequestSwap::HostLobby { .. }) => warn!("Could not host lobby: {err:?}"),
+                    Some(RequestSwap::UpdateLobby { .. }) => warn!("Could not update our lobby: {err:?}"),
+                    Some(RequestSwap::JoinLobby { lobby }) => warn!("Could not join lobby {lobby}: {err:?}"),
+                    None => warn!("Server reported an error: {err:?}"),
+                }
+                //A failed request only ends what we were waiting on, not a lobby or punch already under way
+                if matches!(*state, PunchthroughState::Idle | PunchthroughState::Joining { .. }) {
+                    let reason = FailureReason::Server(err.clone());
+                    transition(&mut state, &mut state_changes, PunchthroughState::Failed { reason });
+                }
+                punchthrough_events.send(PunchthroughEvent::Failed {
+                    reason: FailureReason::Server(err),
+                });
+            }
         }
     }
 
+    drive_nat_probe(client_res.as_mut(), &mut punchthrough_events, &mut nat_type, now);
+    receive_relayed(client_res.as_mut());
+    drive_keepalives(client_res.as_mut(), &mut punchthrough_events, now);
+    follow_address_change(client_res.as_mut(), &mut state, &mut state_changes);
+    drive_punch_attempt(client_res.as_mut(), &mut punchthrough_events, &mut state, &mut state_changes, now);
+
     //Probably should just do 1 
     for connect_request in client_connect_request.iter() {
-        match connect_request {
-            RequestSwap::JoinLobby { lobby } => {
-                let swap_req_msg = bincode::serialize(&ClientHostMessage::RequestSwap { lobby_id: lobby.clone() }).expect("Could not serialize request to swap");
-                client_res.client.send_message(ClientChannel::Command.id(), swap_req_msg);
-                info!("Sent Join Lobby Request {connect_request:#?}");
+        if let RequestSwap::JoinLobby { lobby } = connect_request {
+            client_res.hosting = None;
+            transition(&mut state, &mut state_changes, PunchthroughState::Joining { lobby: lobby.clone() });
+        }
+        match (client_res.server_capabilities, &client_res.nat_probe) {
+            (Some(_), None) => send_swap_request(client_res.as_mut(), connect_request.clone(), *nat_type),
+            _ => client_res.queued_requests.push(connect_request.clone()),
+        }
+    }
+}
+
+/// Sends RequestLobbyList to the server and hands out the answers punchthrough_system received
+pub fn lobby_list_system(
+    mut lobby_list_requests: EventReader<RequestLobbyList>,
+    mut lobby_lists: EventWriter<LobbyListReceived>,
+    mut lobby_list_failures: EventWriter<LobbyListFailed>,
+    mut client_res: ResMut<PunchthroughClientRes>,
+) {
+    //Listing doesn't wait for the NAT probe, nothing about our NAT goes into it
+    for request in lobby_list_requests.iter() {
+        match client_res.server_capabilities {
+            Some(_) => send_lobby_list_request(client_res.as_mut(), request.clone()),
+            None => client_res.queued_lobby_lists.push(request.clone()),
+        }
+    }
+    for received in std::mem::take(&mut client_res.lobby_list_inbox) {
+        match received {
+            Ok(list) => lobby_lists.send(list),
+            Err(err) => lobby_list_failures.send(LobbyListFailed { err }),
+        }
+    }
+}
+
+fn is_resume_response(client_res: &PunchthroughClientRes, request_id: Option<RequestId>) -> bool {
+    matches!((&client_res.resume, request_id), (Some(resume), Some(id)) if resume.request == Some(id))
+}
+
+fn is_lobby_list_response(client_res: &PunchthroughClientRes, request_id: Option<RequestId>) -> bool {
+    matches!(request_id, Some(id) if client_res.lobby_list_requests.contains(&id))
+}
+
+fn is_relay_response(client_res: &PunchthroughClientRes, request_id: Option<RequestId>) -> bool {
+    matches!((&client_res.relay_request, request_id), (Some((id, _)), Some(request_id)) if *id == request_id)
+}
+
+/// Moves relayed payloads from the punch inbox to the relay inbox
+fn receive_relayed(client_res: &mut PunchthroughClientRes) {
+    let link = match client_res.relay {
+        Some(link) => link,
+        None => return,
+    };
+
+    let mut received = Vec::new();
+    client_res.punch_inbox.retain(|(from, packet)| match packet {
+        PunchPacket::Relayed { token, payload } if *from == link.relay && *token == link.token => {
+            if !payload.is_empty() {
+                received.push(payload.clone());
+            }
+            false
+        }
+        _ => true,
+    });
+    client_res.relay_inbox.extend(received);
+}
+
+/// Keeps the mappings we depend on open: towards the server while hosting, and towards the punched peer.
+/// Takes the peer's keepalives out of the punch inbox
+fn drive_keepalives(
+    client_res: &mut PunchthroughClientRes,
+    punchthrough_events: &mut EventWriter<PunchthroughEvent>,
+    now: Duration,
+) {
+    let schedule = client_res.keepalive;
+    let server_due = matches!(
+        client_res.server_keepalive_at,
+        Some(at) if now.saturating_sub(at) >= schedule.server_interval
+    );
+    if client_res.hosting.is_some() && server_due && client_res.client.is_connected() {
+        client_res.send_message(ClientToServer::Keepalive);
+        client_res.server_keepalive_at = Some(now);
+        if let Some(check) = client_res.address_check {
+            let keepalive = PunchPacket::AddressKeepalive { token: check.token }.encode();
+            if let Err(e) = send_from(&client_res.punch_socket, &keepalive, check.observer) {
+                warn!("Could not send address keepalive to {} because {e:#?}", check.observer);
+            }
+        }
+    }
+
+    let link = match client_res.peer_link.as_mut() {
+        Some(link) => link,
+        None => return,
+    };
+    client_res.punch_inbox.retain(|(from, packet)| match packet {
+        PunchPacket::Keepalive if *from == link.peer => {
+            link.on_received(now);
+            false
+        }
+        _ => true,
+    });
+
+    if link.keepalive_due(now, &schedule) {
+        if let Err(e) = send_from(&client_res.punch_socket, &PunchPacket::Keepalive.encode(), link.peer) {
+            warn!("Could not send keepalive to {} because {e:#?}", link.peer);
+        }
+        link.on_sent(now);
+    }
 
-            },
-            RequestSwap::HostLobby => {
-                let host_req_msg = bincode::serialize(&ClientHostMessage::HostNewLobby).expect("Could not serialize HostNewLobby Enum to bytes");
-                client_res.client.send_message(ClientChannel::Command.id(), host_req_msg);
-                info!("Sent Host Lobby Request");
+    if link.check_unresponsive(now, &schedule) {
+        warn!("Heard nothing from {} for {:?}", link.peer, schedule.peer_timeout);
+        punchthrough_events.send(PunchthroughEvent::PeerUnresponsive {
+            peer: link.peer,
+            keepalives_sent: link.keepalives_sent,
+            keepalives_received: link.keepalives_received,
+        });
+    }
+}
 
+/// Takes the server's word that our public address moved out of the punch inbox, and connects to it again from there
+/// so it can carry our session over. Answered with PunchthroughEvent::AddressChanged once it did
+fn follow_address_change(
+    client_res: &mut PunchthroughClientRes,
+    state: &mut ResMut<PunchthroughState>,
+    state_changes: &mut EventWriter<PunchthroughStateChanged>,
+) {
+    let check = match client_res.address_check {
+        Some(check) => check,
+        None => return,
+    };
+    let observer = canonical(check.observer);
+    let mut moved = None;
+    client_res.punch_inbox.retain(|(from, packet)| match packet {
+        PunchPacket::AddressMoved { token, observed } if *from == observer && *token == check.token => {
+            moved = Some(*observed);
+            false
+        }
+        _ => true,
+    });
+    let observed = match moved {
+        Some(observed) => observed,
+        None => return,
+    };
+
+    info!("Rendezvous server saw us move to {observed}, connecting again");
+    match client_res.punch_socket.try_clone().and_then(|socket| client_res.reconnect(socket)) {
+        Ok(()) => transition(state, state_changes, PunchthroughState::ConnectingToRendezvous),
+        Err(e) => warn!("Could not connect to the rendezvous server again because {e:#?}"),
+    }
+}
+
+/// Moves to the next state, announcing it if it differs from the current one
+fn transition(
+    state: &mut ResMut<PunchthroughState>,
+    state_changes: &mut EventWriter<PunchthroughStateChanged>,
+    next: PunchthroughState,
+) {
+    if **state == next {
+        return;
+    }
+
+    let previous = std::mem::replace(&mut **state, next.clone());
+    debug!("Punchthrough state {previous:?} -> {next:?}");
+    state_changes.send(PunchthroughStateChanged { previous, current: next });
+}
+
+fn receive_hello_response(
+    client_res: &mut PunchthroughClientRes,
+    punchthrough_events: &mut EventWriter<PunchthroughEvent>,
+    state: &mut ResMut<PunchthroughState>,
+    state_changes: &mut EventWriter<PunchthroughStateChanged>,
+    nat_type: NatType,
+) {
+    let response = match client_res.client.receive_hello_response() {
+        Some(Ok(response)) => response,
+        Some(Err(e)) => {
+            error!("Could not read the server's answer to our Hello {e:#?}");
+            return;
+        }
+        None => return,
+    };
+
+    match response {
+        HelloResponse::Welcome { protocol_version, capabilities } => {
+            info!("Server accepted protocol version {protocol_version}");
+            client_res.server_capabilities = Some(capabilities.intersection(Capabilities::SUPPORTED));
+            if let Some((client_id, resume_token)) = client_res.resume.as_ref().map(|r| (r.client_id, r.resume_token)) {
+                let request_id = client_res.send_message(ClientToServer::Resume { client_id, resume_token });
+                client_res.resume.as_mut().expect("Checked above").request = Some(request_id);
+            }
+            //Joins requested while connecting already moved us on to Joining
+            if **state == PunchthroughState::ConnectingToRendezvous {
+                transition(state, state_changes, PunchthroughState::Idle);
+            }
+            for request in std::mem::take(&mut client_res.queued_requests) {
+                send_swap_request(client_res, request, nat_type);
             }
+            for request in std::mem::take(&mut client_res.queued_lobby_lists) {
+                send_lobby_list_request(client_res, request);
+            }
+        }
+        HelloResponse::Incompatible { server, client } => {
+            error!("Server speaks protocol version {server} but this client speaks {client}");
+            client_res.client.disconnect();
+            client_res.queued_requests.clear();
+            client_res.queued_lobby_lists.clear();
+            let reason = FailureReason::Server(ClientError::IncompatibleVersion { server, client });
+            transition(state, state_changes, PunchthroughState::Failed { reason: reason.clone() });
+            punchthrough_events.send(PunchthroughEvent::Failed { reason });
         }
     }
 }
 
+/// Probes each observer in turn from the punch socket. Each is sent a few times in case some are dropped, the server
+/// only looks at the first to arrive
+fn send_mapping_probes(client_res: &mut PunchthroughClientRes, token: u64, observers: &[SocketAddr]) {
+    let probe = PunchPacket::MappingProbe { token }.encode();
+    for observer in observers {
+        for _ in 0..MAPPING_PROBE_COPIES {
+            if let Err(e) = send_from(&client_res.punch_socket, &probe, *observer) {
+                warn!("Could not send mapping probe to {observer} because {e:#?}");
+            }
+        }
+    }
+}
+
+/// Takes the observers' echoes out of the punch inbox and classifies our NAT once they are all in or stop coming.
+/// Requests held back while the probe ran are sent afterwards so they carry the result
+fn drive_nat_probe(
+    client_res: &mut PunchthroughClientRes,
+    punchthrough_events: &mut EventWriter<PunchthroughEvent>,
+    nat_type: &mut ResMut<NatType>,
+    now: Duration,
+) {
+    let probe = match client_res.nat_probe.as_mut() {
+        Some(probe) => probe,
+        None => return,
+    };
+
+    client_res.punch_inbox.retain(|(from, packet)| match packet {
+        PunchPacket::MappingEcho { token, observed, path } => {
+            probe.on_echo(*from, *token, canonical(*observed), *path);
+            false
+        }
+        _ => true,
+    });
+
+    if !probe.done(now) {
+        return;
+    }
+
+    let classified = probe.classify();
+    info!("Classified our NAT as {classified:?}");
+    **nat_type = classified;
+    client_res.nat_probe = None;
+    punchthrough_events.send(PunchthroughEvent::NatClassified { nat_type: classified });
+
+    if client_res.server_capabilities.is_some() {
+        for request in std::mem::take(&mut client_res.queued_requests) {
+            send_swap_request(client_res, request, classified);
+        }
+    }
+}
+
+fn send_swap_request(client_res: &mut PunchthroughClientRes, request: RequestSwap, nat_type: NatType) {
+    //Gathered per request in case we moved networks since the last one
+    let local_addrs = match client_res.punch_socket.local_addr() {
+        Ok(bound) => local_candidates(bound, client_res.punchthrough_server),
+        Err(_) => Vec::new(),
+    };
+
+    match &request {
+        RequestSwap::JoinLobby { lobby } => {
+            let lobby_id = lobby.clone();
+            info!("Sent Join Lobby Request {request:#?}");
+            let body = ClientToServer::RequestSwap { lobby_id, nat_type, local_addrs };
+            client_res.send_request(body, request);
+        }
+        RequestSwap::HostLobby { info } => {
+            info!("Sent Host Lobby Request");
+            let body = ClientToServer::HostNewLobby { nat_type, local_addrs, info: info.clone() };
+            client_res.send_request(body, request);
+        }
+        RequestSwap::UpdateLobby { info } => {
+            info!("Sent Update Lobby Request");
+            client_res.send_request(ClientToServer::UpdateLobby { info: info.clone() }, request);
+        }
+    }
+}
+
+fn send_lobby_list_request(client_res: &mut PunchthroughClientRes, request: RequestLobbyList) {
+    info!("Sent List Lobbies Request {request:?}");
+    let RequestLobbyList { filter, page } = request;
+    let request_id = client_res.send_message(ClientToServer::ListLobbies { filter, page });
+    client_res.lobby_list_requests.insert(request_id);
+}
+
 pub fn client_connection_config() -> RenetConnectionConfig {
     RenetConnectionConfig {
         send_channels_config: ClientChannel::channels_config(),
@@ -117,60 +926,157 @@ pub fn client_connection_config() -> RenetConnectionConfig {
     }
 }
 
-fn new_renet_client(ptc_plugin: &PunchthroughClientPlugin) -> RenetClient {
-    info!("Binding Local Socket to {}", ptc_plugin.local_socket.to_string());
-    let local_socket = UdpSocket::bind(ptc_plugin.local_socket).unwrap();
-
-    let connection_config = client_connection_config();
+/// Builds the rendezvous client along with a second handle to its socket for sending and receiving punch packets
+fn new_rendezvous_transport(
+    socket: UdpSocket,
+    punchthrough_server: SocketAddr,
+    authentication: &ClientAuth,
+    protocol_id: u64,
+) -> io::Result<(RendezvousTransport, UdpSocket)> {
+    let punch_socket = socket.try_clone()?;
+    punch_socket.set_nonblocking(true)?;
 
     let current_time = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap();
-    let client_id = current_time.as_millis() as u64;
+    let connect_token = match authentication {
+        ClientAuth::Unsecure => {
+            //Client ids have to be unique per server, two clients started in the same millisecond must not collide
+            let client_id = rand::random::<u64>();
+            //An unsecure server checks tokens against the all zero key
+            let token = ConnectToken::generate(
+                current_time,
+                protocol_id,
+                300,
+                client_id,
+                15,
+                vec![punchthrough_server],
+                None,
+                &[0; NETCODE_KEY_BYTES],
+            );
+            token.map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?
+        }
+        //The backend that issued the token picked our client id
+        ClientAuth::Secure { connect_token } => connect_token.clone(),
+    };
+
+    let client = RendezvousTransport::new(current_time, socket, connect_token, client_connection_config());
+    info!("Connecting to rendezvous server {punchthrough_server} as client {}", client.client_id());
+    Ok((client, punch_socket))
+}
 
-    let authentication = ClientAuthentication::Unsecure {
-        client_id,
-        protocol_id: PROTOCOL_ID,
-        server_addr: ptc_plugin.punchthrough_server,
-        user_data: None,
+/// Sends from the punch socket. A dual stack socket can only reach IPv4 addresses mapped into IPv6
+fn send_from(socket: &UdpSocket, bytes: &[u8], to: SocketAddr) -> io::Result<usize> {
+    let local = socket.local_addr()?;
+    socket.send_to(bytes, sendable(to, local))
+}
+
+/// Answers the peer's probes, checks its acks and sends our own probes when they are due
+fn drive_punch_attempt(
+    client_res: &mut PunchthroughClientRes,
+    punchthrough_events: &mut EventWriter<PunchthroughEvent>,
+    state: &mut ResMut<PunchthroughState>,
+    state_changes: &mut EventWriter<PunchthroughStateChanged>,
+    now: Duration,
+) {
+    let attempt = match client_res.punch_attempt.as_mut() {
+        Some(attempt) => attempt,
+        None => {
+            client_res.punch_inbox.clear();
+            return;
+        }
     };
 
-    let client = RenetClient::new(
-        current_time,
-        local_socket,
-        client_id,
-        connection_config,
-        authentication,
-    )
-    .unwrap();
-
-    println!("Constructed new RenetClient with server addr {} and client addr", ptc_plugin.punchthrough_server);
-    client
-}
-
-fn send_pt_packet(local_socket: SocketAddr, target_socket: SocketAddr) -> Result<(), String> {
-    let local_udp_socket = UdpSocket::bind(local_socket);
-
-    match local_udp_socket {
-        Ok(bound_socket) => {
-            match bound_socket.connect(target_socket) {
-                Ok(_) => {
-                    match bound_socket.send("BevyPunchthrough Packet".as_bytes()){
-                        Ok(_something) => {
-                            info!("Sent Handshake Packet");
-                        },
-                        Err(e) => {
-                            warn!("Could not send Handshake Packet because {e:#?}");
-                        }
+    for (from, packet) in std::mem::take(&mut client_res.punch_inbox) {
+        if !attempt.is_candidate(from) {
+            continue;
+        }
+
+        let confirmed = match packet {
+            PunchPacket::Probe { nonce, role, tie_breaker, nominate } => {
+                if attempt.checklist.on_role_conflict(role, tie_breaker) {
+                    info!("Both sides were {role:?}, we are now {:?}", attempt.role());
+                }
+                let ack = PunchPacket::Ack { nonce, observed: from, nominated: nominate };
+                if let Err(e) = send_from(&client_res.punch_socket, &ack.encode(), from) {
+                    warn!("Could not acknowledge probe from {from} because {e:#?}");
+                }
+                nominate && attempt.on_nominated(from)
+            }
+            PunchPacket::Ack { nonce, observed, nominated } => {
+                //Acks on the confirmed pair keep coming while we nominate it, and carry what the game connection
+                //has to be set up under
+                if nonce == attempt.nonce {
+                    attempt.observed.insert(from, canonical(observed));
+                    if let Some(link) = client_res.peer_link.as_mut().filter(|link| link.peer == from) {
+                        link.observed = Some(canonical(observed));
                     }
                 }
-                Err(send_error) => {return Err(send_error.to_string());}
+                attempt.on_ack(from, nonce, nominated)
             }
+            _ => false,
+        };
+
+        if confirmed {
+            info!("Punchthrough to {from} confirmed as {:?}", attempt.role());
+            let mut link = PeerLink::new(from, now);
+            link.observed = attempt.observed.get(&from).copied();
+            if let Some(observed) = link.observed {
+                debug!("Peer sees us as {observed}");
+            }
+            client_res.peer_link = Some(link);
+            transition(state, state_changes, PunchthroughState::Connected { peer: from });
+            punchthrough_events.send(PunchthroughEvent::Success {
+                target_sock: from,
+                local_sock: client_res.punch_socket.local_addr().unwrap(),
+                family: IpFamily::of(from),
+            });
+        }
+    }
 
-            Ok(())
+    // A confirmed attempt is kept until the deadline so we keep acknowledging the peer's probes
+    if let Some(cause) = attempt.expired(now) {
+        let target = attempt.target;
+        if !attempt.confirmed {
+            warn!("Punchthrough to {target} timed out after {} probes ({cause:?})", attempt.probes_sent);
+            let reason = FailureReason::PunchTimeout {
+                target,
+                probes_sent: attempt.probes_sent,
+                cause,
+            };
+            //The relay is the candidate of last resort, only offered when the server relays
+            let relay_supported = matches!(client_res.server_capabilities, Some(c) if c.contains(Capabilities::RELAY));
+            if relay_supported && attempt.checklist.relay.is_some() {
+                let peer = attempt.peer;
+                info!("Asking the server to relay to {peer}");
+                let request_id = client_res.send_message(ClientToServer::RequestRelay { peer });
+                client_res.relay_request = Some((request_id, reason));
+            } else {
+                client_res.send_message(ClientToServer::LeaveLobby);
+                transition(state, state_changes, PunchthroughState::Failed { reason: reason.clone() });
+                punchthrough_events.send(PunchthroughEvent::Failed { reason });
+            }
         }
-        Err(e) => {
-            Err(e.to_string())
+        client_res.punch_attempt = None;
+        return;
+    }
+
+    //The whole opening burst goes out at once, after that at most one probe is due per frame
+    let mut sent = 0;
+    while attempt.probe_due(now) && sent < attempt.schedule.burst.max(1) {
+        let probe = attempt.probe(now).encode();
+        for candidate in attempt.probe_targets().iter() {
+            if let Err(e) = send_from(&client_res.punch_socket, &probe, *candidate) {
+                error!("Could not send punchthrough probe to socket {candidate} because {e:#?}");
+            }
         }
+        sent += 1;
+    }
+    if sent > 0 && !attempt.confirmed {
+        let punching = PunchthroughState::Punching {
+            peer: attempt.target,
+            attempts: attempt.probes_sent,
+        };
+        transition(state, state_changes, punching);
     }
 }
@@ -0,0 +1,278 @@
+use std::{
+    fmt, io,
+    net::{SocketAddr, UdpSocket},
+    time::SystemTime,
+};
+
+use bevy::prelude::*;
+use bevy_renet::renet::{
+    ChannelConfig, ClientAuthentication, ConnectToken, RenetClient, RenetConnectionConfig, RenetServer,
+    ServerAuthentication, ServerConfig, NETCODE_KEY_BYTES,
+};
+
+use crate::{
+    client::{PunchthroughClientRes, PunchthroughState},
+    nat::{canonical, sendable, IpFamily},
+    punch::PeerLink,
+    ClientChannel, ServerChannel, PROTOCOL_ID,
+};
+
+/// Channels of the game's own connection between the peers. The joiner sends on client, the host on server
+#[derive(Debug, Clone)]
+pub struct PeerChannels {
+    pub client: Vec<ChannelConfig>,
+    pub server: Vec<ChannelConfig>,
+}
+
+impl Default for PeerChannels {
+    fn default() -> Self {
+        Self {
+            client: ClientChannel::channels_config(),
+            server: ServerChannel::channels_config(),
+        }
+    }
+}
+
+/// How the game's connection to a punched peer is set up, see hand_off
+#[derive(Debug, Clone)]
+pub struct PeerHandoff {
+    /// Both peers must use the same one. Defaults to PROTOCOL_ID
+    pub protocol_id: u64,
+    pub channels: PeerChannels,
+    /// Joiners the host's RenetServer accepts
+    pub max_clients: usize,
+}
+
+impl Default for PeerHandoff {
+    fn default() -> Self {
+        Self {
+            protocol_id: PROTOCOL_ID,
+            channels: PeerChannels::default(),
+            max_clients: 1,
+        }
+    }
+}
+
+/// The game's connection to the punched peer, on the socket the punch went through
+pub enum PeerConnection {
+    /// We host the lobby, the joiner connects to us
+    Host { server: RenetServer, peer: SocketAddr },
+    /// We joined the lobby and connect to its host
+    Joiner { client: RenetClient, peer: SocketAddr },
+}
+
+#[derive(Debug)]
+pub enum HandoffError {
+    /// Only a punched path can be handed off, not one still punching or going through the relay
+    NotConnected(PunchthroughState),
+    /// The rendezvous client was already handed off
+    HandedOff,
+    Io(io::Error),
+}
+
+impl fmt::Display for HandoffError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotConnected(state) => write!(f, "no punched peer to hand off, punchthrough is {state:?}"),
+            Self::HandedOff => write!(f, "the punched socket was already handed off"),
+            Self::Io(e) => write!(f, "could not set up the peer connection: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for HandoffError {}
+
+impl From<io::Error> for HandoffError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Builds the game's connection to peer on the punched socket, then closes the rendezvous connection. The socket is
+/// never closed in between, so the NAT keeps the mapping the punch opened. A host's lobby closes with the rendezvous
+/// connection. On error the rendezvous connection is left open, so the handoff can be retried
+pub fn into_peer_connection(
+    client_res: &mut PunchthroughClientRes,
+    peer: SocketAddr,
+    handoff: &PeerHandoff,
+) -> Result<PeerConnection, HandoffError> {
+    let connection = peer_connection(client_res, peer, handoff)?;
+    //Only the rendezvous client's handle to the socket is closed with it, the game's handle keeps the port bound
+    client_res.client.disconnect();
+    Ok(connection)
+}
+
+fn peer_connection(
+    client_res: &PunchthroughClientRes,
+    peer: SocketAddr,
+    handoff: &PeerHandoff,
+) -> Result<PeerConnection, HandoffError> {
+    //Refreshes the mapping towards the peer while renet starts up. Neither renet nor the punch code reads empty datagrams
+    let local = client_res.punch_socket.local_addr()?;
+    client_res.punch_socket.send_to(&[], sendable(peer, local))?;
+    let socket = client_res.punch_socket.try_clone()?;
+
+    let current_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap();
+    match client_res.hosting.is_some() {
+        true => {
+            let connection_config = RenetConnectionConfig {
+                send_channels_config: handoff.channels.server.clone(),
+                receive_channels_config: handoff.channels.client.clone(),
+                ..Default::default()
+            };
+            //Netcode only accepts joiners whose token names the address they dial, which behind a NAT isn't ours
+            let public = peer_facing_addr(peer, client_res.peer_link.as_ref(), client_res.public_addr, local);
+            let server_config =
+                ServerConfig::new(handoff.max_clients, handoff.protocol_id, public, ServerAuthentication::Unsecure);
+            let server = RenetServer::new(current_time, server_config, connection_config, socket)?;
+            info!("Handed the punched socket {local} to a RenetServer for {peer}, which reaches us at {public}");
+            Ok(PeerConnection::Host { server, peer })
+        }
+        false => {
+            let client_id = client_res.client.client_id();
+            let client = new_peer_client(socket, client_id, peer_server_addrs(peer, local), handoff)?;
+            info!("Handed the punched socket {local} to a RenetClient connecting to {peer}");
+            Ok(PeerConnection::Joiner { client, peer })
+        }
+    }
+}
+
+/// The address the peer reaches us on, which the host's RenetServer has to be set up under. What the peer's acks
+/// said, else our public address as the rendezvous server sees it, else the address we are bound to
+pub fn peer_facing_addr(
+    peer: SocketAddr,
+    link: Option<&PeerLink>,
+    public_addr: Option<SocketAddr>,
+    local: SocketAddr,
+) -> SocketAddr {
+    link.and_then(|link| link.observed)
+        .or_else(|| public_addr.filter(|addr| IpFamily::of(*addr) == IpFamily::of(peer)))
+        .map(canonical)
+        .unwrap_or(local)
+}
+
+/// Where a joiner's RenetClient dials the host. The first is sendable from our socket, the host is set up under the
+/// plain address it was observed at, so the IPv4 form is listed as well when a dual stack socket has to map it
+pub fn peer_server_addrs(peer: SocketAddr, local: SocketAddr) -> Vec<SocketAddr> {
+    let dialed = sendable(peer, local);
+    match dialed == canonical(peer) {
+        true => vec![dialed],
+        false => vec![dialed, canonical(peer)],
+    }
+}
+
+fn new_peer_client(
+    socket: UdpSocket,
+    client_id: u64,
+    server_addrs: Vec<SocketAddr>,
+    handoff: &PeerHandoff,
+) -> io::Result<RenetClient> {
+    let connection_config = RenetConnectionConfig {
+        send_channels_config: handoff.channels.client.clone(),
+        receive_channels_config: handoff.channels.server.clone(),
+        ..Default::default()
+    };
+    let current_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap();
+    //Peers have no backend to issue connect tokens, the punch already settled who is on the other end. The host's
+    //unsecure server checks tokens against the all zero key
+    let connect_token = ConnectToken::generate(
+        current_time,
+        handoff.protocol_id,
+        300,
+        client_id,
+        15,
+        server_addrs,
+        None,
+        &[0; NETCODE_KEY_BYTES],
+    )
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let authentication = ClientAuthentication::Secure { connect_token };
+    RenetClient::new(current_time, socket, client_id, connection_config, authentication)
+}
+
+/// Swaps PunchthroughClientRes for the game's RenetServer or RenetClient resource once punchthrough is Connected, for
+/// bevy_renet's RenetServerPlugin or RenetClientPlugin to drive. Returns the peer's address. On error the world is left
+/// as it was
+pub fn hand_off(world: &mut World, handoff: &PeerHandoff) -> Result<SocketAddr, HandoffError> {
+    let peer = match world.resource::<PunchthroughState>() {
+        PunchthroughState::Connected { peer } => *peer,
+        state => return Err(HandoffError::NotConnected(state.clone())),
+    };
+    let connection = {
+        let mut client_res = world
+            .get_resource_mut::<PunchthroughClientRes>()
+            .ok_or(HandoffError::HandedOff)?;
+        into_peer_connection(&mut client_res, peer, handoff)?
+    };
+
+    world.remove_resource::<PunchthroughClientRes>();
+    match connection {
+        PeerConnection::Host { server, .. } => world.insert_resource(server),
+        PeerConnection::Joiner { client, .. } => world.insert_resource(client),
+    }
+    Ok(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::ClientAuth, client::PunchthroughClientPlugin, punch::{KeepaliveSchedule, PunchSchedule}};
+    use bevy::MinimalPlugins;
+    use std::time::Duration;
+
+    #[test]
+    fn failed_handoff_keeps_the_rendezvous_client() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins).add_plugin(PunchthroughClientPlugin {
+            local_socket: "127.0.0.1:0".parse().unwrap(),
+            punchthrough_server: "127.0.0.1:5191".parse().unwrap(),
+            authentication: ClientAuth::Unsecure,
+            protocol_id: PROTOCOL_ID,
+            punch_schedule: PunchSchedule::default(),
+            keepalive: KeepaliveSchedule::default(),
+        });
+        //An IPv4 socket can't send to an IPv6 peer, so the handoff fails before renet is set up
+        let peer: SocketAddr = "[2001:db8::7]:41000".parse().unwrap();
+        app.insert_resource(PunchthroughState::Connected { peer });
+
+        for _ in 0..2 {
+            assert!(matches!(hand_off(&mut app.world, &PeerHandoff::default()), Err(HandoffError::Io(_))));
+            assert_eq!(*app.world.resource::<PunchthroughState>(), PunchthroughState::Connected { peer });
+            let client_res = app.world.resource::<PunchthroughClientRes>();
+            assert!(client_res.client.disconnected().is_none());
+        }
+        assert!(app.world.get_resource::<RenetServer>().is_none());
+        assert!(app.world.get_resource::<RenetClient>().is_none());
+    }
+
+    #[test]
+    fn host_is_served_under_the_address_the_peer_sees() {
+        let local: SocketAddr = "0.0.0.0:5000".parse().unwrap();
+        let peer: SocketAddr = "198.51.100.7:41000".parse().unwrap();
+        let nat: SocketAddr = "203.0.113.9:62011".parse().unwrap();
+        let public: SocketAddr = "203.0.113.9:62000".parse().unwrap();
+
+        let mut link = PeerLink::new(peer, Duration::ZERO);
+        assert_eq!(peer_facing_addr(peer, Some(&link), Some(public), local), public);
+        link.observed = Some(nat);
+        assert_eq!(peer_facing_addr(peer, Some(&link), Some(public), local), nat);
+        assert_eq!(peer_facing_addr(peer, None, None, local), local);
+
+        //A public address of the other family is not where this peer reaches us
+        let v6_peer: SocketAddr = "[2001:db8::7]:41000".parse().unwrap();
+        assert_eq!(peer_facing_addr(v6_peer, None, Some(public), local), local);
+    }
+
+    #[test]
+    fn joiners_list_the_host_both_ways_from_dual_stack_sockets() {
+        let host: SocketAddr = "203.0.113.9:62011".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:203.0.113.9]:62011".parse().unwrap();
+
+        assert_eq!(peer_server_addrs(host, "0.0.0.0:0".parse().unwrap()), vec![host]);
+        assert_eq!(peer_server_addrs(host, "[::]:0".parse().unwrap()), vec![mapped, host]);
+    }
+}
@@ -1,42 +1,146 @@
-use std::{time::Duration, net::{SocketAddr}};
+use std::time::Duration;
 
-use bevy_renet::renet::{NETCODE_KEY_BYTES, ChannelConfig, ReliableChannelConfig};
-use serde::{Deserialize, Serialize};
+use bevy_renet::renet::{ChannelConfig, ReliableChannelConfig, RenetClient, RenetServer};
+use renet_plugin::PTRenetServer;
+use transport::RendezvousTransport;
 
 pub mod server;
 pub mod client;
+pub mod auth;
+pub mod candidate;
+pub mod handoff;
+pub mod lobby;
+pub mod nat;
+pub mod protocol;
+pub mod punch;
+pub mod relay;
 pub mod renet_plugin;
+pub mod settings;
+pub mod transport;
 
 pub use bevy_renet;
+pub use protocol::{ClientError, ClientMessage, Hello, HelloResponse, ServerMessage};
 
-pub const PRIVATE_KEY: &[u8; NETCODE_KEY_BYTES] = b"an example very very secret key."; // 32-bytes
+/// Netcode protocol id. This must never change, otherwise clients from other builds can't connect far enough
+/// to be told they are incompatible. The message format is versioned by protocol::PROTOCOL_VERSION instead
 pub const PROTOCOL_ID: u64 = 7;
 
 pub enum ClientChannel {
     Input,
     Command,
+    /// Carries nothing but the Hello, so it is never mistaken for a ClientMessage or the other way round
+    Handshake,
 }
 
 pub enum ServerChannel {
     ServerMessages,
     NetworkFrame,
+    /// Carries nothing but the HelloResponse, see ClientChannel::Handshake
+    Handshake,
 }
 
-///Informs the server that the application at address would like to allow punch through connections
-///Server will store this info and make it available for SwapRequests until it receives the disconnect event
-#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
-pub enum ClientHostMessage{
-    HostNewLobby,
-    NewLobbyResponse {lobby_id: String},
-    RequestSwap {lobby_id: String},
-    JoinLobbyResponse {err: Option<ClientError>},
-    AttemptHandshakeCommand {socket: SocketAddr}
+/// Typed access to the punchthrough channels from the client side
+pub trait ClientMessaging {
+    fn send_hello(&mut self, hello: Hello);
+    fn receive_hello_response(&mut self) -> Option<Result<HelloResponse, bincode::Error>>;
+    fn send_to_server(&mut self, message: ClientMessage);
+    fn receive_from_server(&mut self) -> Option<Result<ServerMessage, bincode::Error>>;
 }
 
-#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub enum ClientError{
-    LobbyNotFound {lobby: String},
-    InternalServerError,
+/// Typed access to the punchthrough channels from the server side
+pub trait ServerMessaging {
+    fn send_hello_response(&mut self, client_id: u64, response: HelloResponse);
+    fn receive_hello(&mut self, client_id: u64) -> Option<Result<Hello, bincode::Error>>;
+    fn send_to_client(&mut self, client_id: u64, message: ServerMessage);
+    fn receive_from_client(&mut self, client_id: u64) -> Option<Result<ClientMessage, bincode::Error>>;
+}
+
+impl ClientMessaging for RenetClient {
+    fn send_hello(&mut self, hello: Hello) {
+        let bytes = bincode::serialize(&hello).expect("Could not serialize Hello to bytes");
+        self.send_message(ClientChannel::Handshake.id(), bytes);
+    }
+
+    fn receive_hello_response(&mut self) -> Option<Result<HelloResponse, bincode::Error>> {
+        let bytes = self.receive_message(ServerChannel::Handshake.id())?;
+        Some(bincode::deserialize(&bytes))
+    }
+
+    fn send_to_server(&mut self, message: ClientMessage) {
+        let bytes = bincode::serialize(&message).expect("Could not serialize ClientMessage to bytes");
+        self.send_message(ClientChannel::Command.id(), bytes);
+    }
+
+    fn receive_from_server(&mut self) -> Option<Result<ServerMessage, bincode::Error>> {
+        let bytes = self.receive_message(ServerChannel::ServerMessages.id())?;
+        Some(bincode::deserialize(&bytes))
+    }
+}
+
+impl ClientMessaging for RendezvousTransport {
+    fn send_hello(&mut self, hello: Hello) {
+        let bytes = bincode::serialize(&hello).expect("Could not serialize Hello to bytes");
+        self.send_message(ClientChannel::Handshake.id(), bytes);
+    }
+
+    fn receive_hello_response(&mut self) -> Option<Result<HelloResponse, bincode::Error>> {
+        let bytes = self.receive_message(ServerChannel::Handshake.id())?;
+        Some(bincode::deserialize(&bytes))
+    }
+
+    fn send_to_server(&mut self, message: ClientMessage) {
+        let bytes = bincode::serialize(&message).expect("Could not serialize ClientMessage to bytes");
+        self.send_message(ClientChannel::Command.id(), bytes);
+    }
+
+    fn receive_from_server(&mut self) -> Option<Result<ServerMessage, bincode::Error>> {
+        let bytes = self.receive_message(ServerChannel::ServerMessages.id())?;
+        Some(bincode::deserialize(&bytes))
+    }
+}
+
+impl ServerMessaging for RenetServer {
+    fn send_hello_response(&mut self, client_id: u64, response: HelloResponse) {
+        let bytes = bincode::serialize(&response).expect("Could not serialize HelloResponse to bytes");
+        self.send_message(client_id, ServerChannel::Handshake.id(), bytes);
+    }
+
+    fn receive_hello(&mut self, client_id: u64) -> Option<Result<Hello, bincode::Error>> {
+        let bytes = self.receive_message(client_id, ClientChannel::Handshake.id())?;
+        Some(bincode::deserialize(&bytes))
+    }
+
+    fn send_to_client(&mut self, client_id: u64, message: ServerMessage) {
+        let bytes = bincode::serialize(&message).expect("Could not serialize ServerMessage to bytes");
+        self.send_message(client_id, ServerChannel::ServerMessages.id(), bytes);
+    }
+
+    fn receive_from_client(&mut self, client_id: u64) -> Option<Result<ClientMessage, bincode::Error>> {
+        let bytes = self.receive_message(client_id, ClientChannel::Command.id())?;
+        Some(bincode::deserialize(&bytes))
+    }
+}
+
+impl ServerMessaging for PTRenetServer {
+    fn send_hello_response(&mut self, client_id: u64, response: HelloResponse) {
+        if let Some(endpoint) = self.endpoint_of(client_id) {
+            endpoint.send_hello_response(client_id, response);
+        }
+    }
+
+    fn receive_hello(&mut self, client_id: u64) -> Option<Result<Hello, bincode::Error>> {
+        self.endpoint_of(client_id)?.receive_hello(client_id)
+    }
+
+    fn send_to_client(&mut self, client_id: u64, message: ServerMessage) {
+        if let Some(endpoint) = self.endpoint_of(client_id) {
+            endpoint.send_to_client(client_id, message);
+        }
+    }
+
+    fn receive_from_client(&mut self, client_id: u64) -> Option<Result<ClientMessage, bincode::Error>> {
+        self.endpoint_of(client_id)?.receive_from_client(client_id)
+    }
 }
 
 impl ClientChannel {
@@ -44,6 +148,7 @@ impl ClientChannel {
         match self {
             Self::Input => 0,
             Self::Command => 1,
+            Self::Handshake => 2,
         }
     }
 
@@ -61,6 +166,12 @@ impl ClientChannel {
                 ..Default::default()
             }
             .into(),
+            ReliableChannelConfig {
+                channel_id: Self::Handshake.id(),
+                message_resend_time: Duration::ZERO,
+                ..Default::default()
+            }
+            .into(),
         ]
     }
 }
@@ -70,6 +181,7 @@ impl ServerChannel {
         match self {
             Self::NetworkFrame => 0,
             Self::ServerMessages => 1,
+            Self::Handshake => 2,
         }
     }
 
@@ -86,6 +198,12 @@ impl ServerChannel {
                 ..Default::default()
             }
             .into(),
+            ReliableChannelConfig {
+                channel_id: Self::Handshake.id(),
+                message_resend_time: Duration::from_millis(200),
+                ..Default::default()
+            }
+            .into(),
         ]
     }
 }
@@ -0,0 +1,640 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+};
+
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::{nat::NatType, protocol::ClientRole};
+
+/// How many codes are drawn for a new lobby before giving up on finding a free one
+pub const MAX_CODE_ATTEMPTS: usize = 64;
+
+/// Characters that are easily misread or misheard when a code is read aloud
+pub const AMBIGUOUS_CHARS: &str = "0O1IL";
+
+/// Produces the codes players use to join lobbies. Implement this to hand out codes in a custom format
+pub trait LobbyCodeGenerator: Send + Sync {
+    fn generate(&self, rng: &mut dyn RngCore) -> String;
+
+    /// Maps a code as typed by a player onto the form `generate` produces
+    fn normalize(&self, code: &str) -> String {
+        code.trim().to_ascii_uppercase()
+    }
+}
+
+/// Fixed length codes drawn from an alphabet, e.g. "K7QX2"
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlphabetCodeGenerator {
+    pub length: usize,
+    pub alphabet: String,
+    /// Leaves out the characters in AMBIGUOUS_CHARS
+    pub exclude_ambiguous: bool,
+}
+
+impl Default for AlphabetCodeGenerator {
+    fn default() -> Self {
+        Self {
+            length: 5,
+            alphabet: "ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789".to_string(),
+            exclude_ambiguous: false,
+        }
+    }
+}
+
+impl AlphabetCodeGenerator {
+    /// The characters codes are actually drawn from
+    pub fn charset(&self) -> Vec<char> {
+        let mut charset: Vec<char> = self
+            .alphabet
+            .to_ascii_uppercase()
+            .chars()
+            .filter(|c| !(self.exclude_ambiguous && AMBIGUOUS_CHARS.contains(*c)))
+            .collect();
+        charset.sort_unstable();
+        charset.dedup();
+        charset
+    }
+}
+
+impl LobbyCodeGenerator for AlphabetCodeGenerator {
+    fn generate(&self, rng: &mut dyn RngCore) -> String {
+        let charset = self.charset();
+        assert!(!charset.is_empty(), "Lobby code alphabet has no usable characters");
+
+        (0..self.length)
+            .map(|_| charset[rng.gen_range(0..charset.len())])
+            .collect()
+    }
+}
+
+/// Codes made of words and a number, e.g. "BLUE-FOX-42"
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WordCodeGenerator {
+    pub adjectives: Vec<String>,
+    pub nouns: Vec<String>,
+    /// Codes end in a number below this. Zero leaves the number off
+    pub number_below: u32,
+    pub separator: char,
+}
+
+impl Default for WordCodeGenerator {
+    fn default() -> Self {
+        let words = |list: &[&str]| list.iter().map(|word| word.to_string()).collect();
+        Self {
+            adjectives: words(&[
+                "RED", "BLUE", "GREEN", "GOLD", "PINK", "GREY", "TEAL", "BOLD", "CALM", "WILD", "FAST", "TINY",
+            ]),
+            nouns: words(&[
+                "FOX", "OWL", "BEAR", "WOLF", "CROW", "DEER", "FROG", "HAWK", "MOLE", "SEAL", "TOAD", "YAK",
+            ]),
+            number_below: 100,
+            separator: '-',
+        }
+    }
+}
+
+impl LobbyCodeGenerator for WordCodeGenerator {
+    fn generate(&self, rng: &mut dyn RngCore) -> String {
+        let adjective = &self.adjectives[rng.gen_range(0..self.adjectives.len())];
+        let noun = &self.nouns[rng.gen_range(0..self.nouns.len())];
+
+        let mut code = format!("{adjective}{}{noun}", self.separator);
+        if self.number_below > 0 {
+            code.push(self.separator);
+            code.push_str(&rng.gen_range(0..self.number_below).to_string());
+        }
+        code.to_ascii_uppercase()
+    }
+
+    fn normalize(&self, code: &str) -> String {
+        code.trim()
+            .split(|c: char| c.is_whitespace() || c == '-' || c == '_' || c == self.separator)
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join(&self.separator.to_string())
+            .to_ascii_uppercase()
+    }
+}
+
+/// What a host publishes about its lobby. Sent with HostNewLobby, replaced with UpdateLobby while the lobby is open
+/// and handed to joiners in JoinLobbyResponse
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct LobbyInfo {
+    pub name: String,
+    pub map: String,
+    pub game_mode: String,
+    /// Players in the game including the host. The server counts every joiner it introduces, the host's updates
+    /// replace that count, for example when a joiner never made it into the game
+    pub players: u32,
+    /// Joins are refused once players reaches this. 0 means no limit
+    pub max_players: u32,
+    /// Only advertised, the password itself is checked by the host's game
+    pub password_required: bool,
+    /// Listed by ListLobbies. Private lobbies can only be joined by their code
+    pub public: bool,
+    /// Anything else the game wants to publish
+    pub properties: BTreeMap<String, String>,
+}
+
+impl LobbyInfo {
+    pub fn is_full(&self) -> bool {
+        self.max_players != 0 && self.players >= self.max_players
+    }
+
+    /// Bytes of text it carries, see LobbySettings::max_info_bytes
+    pub fn text_len(&self) -> usize {
+        let properties: usize = self.properties.iter().map(|(key, value)| key.len() + value.len()).sum();
+        self.name.len() + self.map.len() + self.game_mode.len() + properties
+    }
+
+    /// name, map and game_mode are looked up in their fields, any other key in properties
+    pub fn value_of(&self, key: &str) -> Option<&str> {
+        match key {
+            "name" => Some(&self.name),
+            "map" => Some(&self.map),
+            "game_mode" => Some(&self.game_mode),
+            _ => self.properties.get(key).map(String::as_str),
+        }
+    }
+}
+
+/// Which public lobbies ListLobbies returns, and in what order
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct LobbyFilter {
+    /// Every key must have exactly this value, see LobbyInfo::value_of
+    pub equals: BTreeMap<String, String>,
+    /// Leaves out lobbies that can't take another player
+    pub hide_full: bool,
+    pub hide_password_required: bool,
+    pub sort: LobbySort,
+}
+
+impl LobbyFilter {
+    pub fn matches(&self, info: &LobbyInfo) -> bool {
+        info.public
+            && !(self.hide_full && info.is_full())
+            && !(self.hide_password_required && info.password_required)
+            && self.equals.iter().all(|(key, value)| info.value_of(key) == Some(value.as_str()))
+    }
+}
+
+/// Lobbies that sort the same are ordered by code, so pages don't shuffle between requests
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LobbySort {
+    #[default]
+    MostPlayers,
+    FewestPlayers,
+    /// Alphabetical, ignoring case
+    Name,
+}
+
+/// A page of ListLobbies results, index counts from 0
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LobbyPage {
+    pub index: u32,
+    /// Capped by LobbySettings::max_page_size
+    pub size: u32,
+}
+
+impl Default for LobbyPage {
+    fn default() -> Self {
+        Self { index: 0, size: 20 }
+    }
+}
+
+/// A public lobby as ListLobbies returns it
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct LobbyListing {
+    pub lobby_id: String,
+    pub info: LobbyInfo,
+}
+
+/// A lobby registered by a host on the rendezvous server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lobby {
+    pub id: String,
+    pub host_id: u64,
+    /// Public address of the host as observed by the server
+    pub host_addr: SocketAddr,
+    /// As classified by the host when it opened the lobby
+    pub host_nat: NatType,
+    /// The host's addresses on its own network, for joiners behind the same NAT
+    pub host_local_addrs: Vec<SocketAddr>,
+    /// Clients that asked to join this lobby and are still connected to the server
+    pub joiners: HashSet<u64>,
+    pub info: LobbyInfo,
+}
+
+/// Every lobby on the server, indexed by lobby id, by host and by joiner.
+/// All mutation goes through this type so the indexes can't drift apart
+#[derive(Debug, Default)]
+pub struct LobbyRegistry {
+    lobbies: HashMap<String, Lobby>,
+    by_host: HashMap<u64, String>,
+    by_joiner: HashMap<u64, String>,
+}
+
+impl LobbyRegistry {
+    /// Registers a new lobby. A host only ever has one lobby, so any lobby it already had is closed and returned
+    pub fn insert(
+        &mut self,
+        id: String,
+        host_id: u64,
+        host_addr: SocketAddr,
+        host_nat: NatType,
+        host_local_addrs: Vec<SocketAddr>,
+        info: LobbyInfo,
+    ) -> Option<Lobby> {
+        let previous = self.close_lobby_of(host_id);
+
+        self.by_host.insert(host_id, id.clone());
+        self.lobbies.insert(
+            id.clone(),
+            Lobby {
+                id,
+                host_id,
+                host_addr,
+                host_nat,
+                host_local_addrs,
+                joiners: HashSet::new(),
+                info,
+            },
+        );
+
+        previous
+    }
+
+    /// Draws codes until one is found that no active lobby uses. None if the code space looks exhausted
+    pub fn unused_code(&self, generator: &dyn LobbyCodeGenerator, rng: &mut dyn RngCore) -> Option<String> {
+        (0..MAX_CODE_ATTEMPTS)
+            .map(|_| generator.generate(rng))
+            .find(|code| !self.contains(code))
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Lobby> {
+        self.lobbies.get(id)
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.lobbies.contains_key(id)
+    }
+
+    pub fn lobby_of_host(&self, host_id: u64) -> Option<&Lobby> {
+        self.by_host.get(&host_id).and_then(|id| self.lobbies.get(id))
+    }
+
+    pub fn role_of(&self, client_id: u64) -> ClientRole {
+        if self.by_host.contains_key(&client_id) {
+            ClientRole::Host
+        } else if self.by_joiner.contains_key(&client_id) {
+            ClientRole::Joiner
+        } else {
+            ClientRole::Idle
+        }
+    }
+
+    /// Clients this one has been introduced to: a host's joiners, or the host of the lobby a joiner is joining
+    pub fn counterparts(&self, client_id: u64) -> Vec<u64> {
+        if let Some(lobby) = self.lobby_of_host(client_id) {
+            return lobby.joiners.iter().copied().collect();
+        }
+
+        self.by_joiner
+            .get(&client_id)
+            .and_then(|id| self.lobbies.get(id))
+            .map(|lobby| vec![lobby.host_id])
+            .unwrap_or_default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lobbies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lobbies.is_empty()
+    }
+
+    /// One page of the public lobbies that match filter, along with how many match in total
+    pub fn list(&self, filter: &LobbyFilter, page: LobbyPage) -> (Vec<LobbyListing>, usize) {
+        let mut matching: Vec<&Lobby> = self.lobbies.values().filter(|lobby| filter.matches(&lobby.info)).collect();
+        matching.sort_by(|a, b| a.id.cmp(&b.id));
+        match filter.sort {
+            LobbySort::MostPlayers => matching.sort_by_key(|lobby| Reverse(lobby.info.players)),
+            LobbySort::FewestPlayers => matching.sort_by_key(|lobby| lobby.info.players),
+            LobbySort::Name => matching.sort_by_key(|lobby| lobby.info.name.to_lowercase()),
+        }
+
+        let total = matching.len();
+        let listings = matching
+            .into_iter()
+            .skip(page.index as usize * page.size as usize)
+            .take(page.size as usize)
+            .map(|lobby| LobbyListing {
+                lobby_id: lobby.id.clone(),
+                info: lobby.info.clone(),
+            })
+            .collect();
+        (listings, total)
+    }
+
+    /// Records that a client is joining a lobby, moving it out of any lobby it was previously joining.
+    /// A joiner is counted towards the lobby's players the first time it asks
+    pub fn add_joiner(&mut self, id: &str, joiner_id: u64) -> Option<&Lobby> {
+        if !self.lobbies.contains_key(id) {
+            return None;
+        }
+
+        if self.by_joiner.get(&joiner_id).map(String::as_str) != Some(id) {
+            self.remove_joiner(joiner_id);
+        }
+        self.by_joiner.insert(joiner_id, id.to_string());
+        let lobby = self.lobbies.get_mut(id)?;
+        if lobby.joiners.insert(joiner_id) {
+            lobby.info.players = lobby.info.players.saturating_add(1);
+        }
+        Some(lobby)
+    }
+
+    /// Replaces what a host publishes about its lobby. None if the client isn't hosting
+    pub fn update_info(&mut self, host_id: u64, info: LobbyInfo) -> Option<&Lobby> {
+        let id = self.by_host.get(&host_id)?;
+        let lobby = self.lobbies.get_mut(id)?;
+        lobby.info = info;
+        Some(lobby)
+    }
+
+    /// Points joiners of a host's lobby at its new public address. None if the client isn't hosting
+    pub fn set_host_addr(&mut self, host_id: u64, host_addr: SocketAddr) -> Option<&Lobby> {
+        let id = self.by_host.get(&host_id)?;
+        let lobby = self.lobbies.get_mut(id)?;
+        lobby.host_addr = host_addr;
+        Some(lobby)
+    }
+
+    /// Hands whatever lobby a client hosts or is joining to the client it resumed its session as, see
+    /// ClientToServer::Resume. A hosted lobby points joiners at host_addr from now on
+    pub fn transfer(&mut self, from: u64, to: u64, host_addr: SocketAddr) {
+        if let Some(id) = self.by_host.remove(&from) {
+            if let Some(lobby) = self.lobbies.get_mut(&id) {
+                lobby.host_id = to;
+                lobby.host_addr = host_addr;
+            }
+            self.by_host.insert(to, id);
+        }
+
+        if let Some(id) = self.by_joiner.remove(&from) {
+            if let Some(lobby) = self.lobbies.get_mut(&id) {
+                lobby.joiners.remove(&from);
+                lobby.joiners.insert(to);
+            }
+            self.by_joiner.insert(to, id);
+        }
+    }
+
+    /// Removes every trace of a disconnected client. If it was hosting, its lobby is closed and returned
+    pub fn remove_client(&mut self, client_id: u64) -> Option<Lobby> {
+        self.remove_joiner(client_id);
+        self.close_lobby_of(client_id)
+    }
+
+    /// Stops a client joining whatever lobby it was joining, freeing up the player slot it was counted in
+    pub fn remove_joiner(&mut self, joiner_id: u64) {
+        if let Some(id) = self.by_joiner.remove(&joiner_id) {
+            if let Some(lobby) = self.lobbies.get_mut(&id) {
+                if lobby.joiners.remove(&joiner_id) {
+                    lobby.info.players = lobby.info.players.saturating_sub(1);
+                }
+            }
+        }
+    }
+
+    fn close_lobby_of(&mut self, host_id: u64) -> Option<Lobby> {
+        let id = self.by_host.remove(&host_id)?;
+        let lobby = self.lobbies.remove(&id)?;
+        for joiner in lobby.joiners.iter() {
+            self.by_joiner.remove(joiner);
+        }
+
+        Some(lobby)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn host_disconnect_closes_lobby() {
+        let mut registry = LobbyRegistry::default();
+        registry.insert("ABCDE".to_string(), 1, addr(1000), NatType::Unknown, Vec::new(), LobbyInfo::default());
+        registry.add_joiner("ABCDE", 2).unwrap();
+        registry.add_joiner("ABCDE", 3).unwrap();
+        let mut joiners = registry.counterparts(1);
+        joiners.sort();
+        assert_eq!(joiners, [2, 3]);
+        assert_eq!(registry.counterparts(2), [1]);
+
+        let closed = registry.remove_client(1).expect("Host should have had a lobby");
+        assert_eq!(closed.joiners, HashSet::from([2, 3]));
+        assert!(registry.is_empty());
+        assert!(registry.lobby_of_host(1).is_none());
+        assert!(registry.add_joiner("ABCDE", 2).is_none());
+
+        // Joiners of the closed lobby are no longer indexed anywhere
+        registry.insert("FGHIJ".to_string(), 4, addr(1001), NatType::Unknown, Vec::new(), LobbyInfo::default());
+        registry.add_joiner("FGHIJ", 2).unwrap();
+        assert_eq!(registry.remove_client(2), None);
+        assert!(registry.get("FGHIJ").unwrap().joiners.is_empty());
+    }
+
+    #[test]
+    fn rehosting_replaces_previous_lobby() {
+        let mut registry = LobbyRegistry::default();
+        registry.insert("ABCDE".to_string(), 1, addr(1000), NatType::Unknown, Vec::new(), LobbyInfo::default());
+        registry.add_joiner("ABCDE", 2).unwrap();
+
+        let previous = registry.insert("FGHIJ".to_string(), 1, addr(1000), NatType::Unknown, Vec::new(), LobbyInfo::default()).unwrap();
+        assert_eq!(previous.id, "ABCDE");
+        assert_eq!(registry.len(), 1);
+        assert_eq!(registry.lobby_of_host(1).unwrap().id, "FGHIJ");
+    }
+
+    #[test]
+    fn joiners_count_towards_capacity() {
+        let mut registry = LobbyRegistry::default();
+        let info = LobbyInfo {
+            name: "Friday night".to_string(),
+            players: 1,
+            max_players: 3,
+            properties: BTreeMap::from([("region".to_string(), "eu".to_string())]),
+            ..Default::default()
+        };
+        assert_eq!(info.text_len(), "Friday night".len() + "regioneu".len());
+        registry.insert("ABCDE".to_string(), 1, addr(1000), NatType::Unknown, Vec::new(), info.clone());
+
+        registry.add_joiner("ABCDE", 2).unwrap();
+        registry.add_joiner("ABCDE", 2).unwrap();
+        assert!(!registry.get("ABCDE").unwrap().info.is_full());
+        assert!(registry.add_joiner("ABCDE", 3).unwrap().info.is_full());
+
+        //The host knows better, one joiner never made it into the game
+        let updated = registry.update_info(1, LobbyInfo { players: 2, ..info }).unwrap();
+        assert!(!updated.info.is_full());
+        assert!(registry.update_info(2, LobbyInfo::default()).is_none());
+    }
+
+    #[test]
+    fn leaving_joiners_free_their_slot() {
+        let mut registry = LobbyRegistry::default();
+        let info = LobbyInfo {
+            players: 1,
+            max_players: 2,
+            ..Default::default()
+        };
+        registry.insert("ABCDE".to_string(), 1, addr(1000), NatType::Unknown, Vec::new(), info.clone());
+        registry.insert("FGHIJ".to_string(), 4, addr(1001), NatType::Unknown, Vec::new(), info);
+
+        //Disconnecting, switching lobbies and giving up on the punch all count the joiner back out
+        assert!(registry.add_joiner("ABCDE", 2).unwrap().info.is_full());
+        registry.remove_client(2);
+        assert!(registry.add_joiner("ABCDE", 3).unwrap().info.is_full());
+        registry.add_joiner("FGHIJ", 3).unwrap();
+        assert_eq!(registry.get("ABCDE").unwrap().info.players, 1);
+        registry.remove_joiner(3);
+        assert!(registry.add_joiner("ABCDE", 5).unwrap().info.is_full());
+        assert_eq!(registry.get("FGHIJ").unwrap().info.players, 1);
+
+        //A host that lowered the count itself never goes below zero
+        registry.update_info(1, LobbyInfo { players: 0, max_players: 2, ..Default::default() }).unwrap();
+        registry.remove_joiner(5);
+        assert_eq!(registry.get("ABCDE").unwrap().info.players, 0);
+    }
+
+    #[test]
+    fn resumed_sessions_keep_their_lobby() {
+        let mut registry = LobbyRegistry::default();
+        registry.insert("ABCDE".to_string(), 1, addr(1000), NatType::Unknown, Vec::new(), LobbyInfo::default());
+        registry.add_joiner("ABCDE", 2).unwrap();
+        assert_eq!(registry.set_host_addr(1, addr(1001)).unwrap().host_addr, addr(1001));
+        assert!(registry.set_host_addr(2, addr(1002)).is_none());
+
+        registry.transfer(1, 3, addr(1003));
+        registry.transfer(2, 4, addr(1004));
+        let lobby = registry.lobby_of_host(3).unwrap();
+        assert_eq!((lobby.host_id, lobby.host_addr), (3, addr(1003)));
+        assert_eq!(lobby.joiners, HashSet::from([4]));
+        assert_eq!(registry.role_of(1), ClientRole::Idle);
+
+        //The old sessions disconnecting later leaves the lobby alone
+        assert_eq!(registry.remove_client(1), None);
+        assert_eq!(registry.remove_client(2), None);
+        assert_eq!(registry.counterparts(3), [4]);
+    }
+
+    #[test]
+    fn only_matching_public_lobbies_are_listed() {
+        let mut registry = LobbyRegistry::default();
+        let public = |name: &str, players: u32, map: &str| LobbyInfo {
+            name: name.to_string(),
+            map: map.to_string(),
+            players,
+            max_players: 4,
+            public: true,
+            ..Default::default()
+        };
+        registry.insert("AAAAA".to_string(), 1, addr(1000), NatType::Unknown, Vec::new(), public("bravo", 2, "dust"));
+        registry.insert("BBBBB".to_string(), 2, addr(1001), NatType::Unknown, Vec::new(), public("Alpha", 4, "dust"));
+        registry.insert("CCCCC".to_string(), 3, addr(1002), NatType::Unknown, Vec::new(), public("charlie", 2, "ice"));
+        let private = LobbyInfo {
+            public: false,
+            ..public("delta", 1, "dust")
+        };
+        registry.insert("DDDDD".to_string(), 4, addr(1003), NatType::Unknown, Vec::new(), private);
+
+        let ids = |(listings, _): (Vec<LobbyListing>, usize)| -> Vec<String> {
+            listings.into_iter().map(|listing| listing.lobby_id).collect()
+        };
+        let page = LobbyPage::default();
+        assert_eq!(ids(registry.list(&LobbyFilter::default(), page)), ["BBBBB", "AAAAA", "CCCCC"]);
+
+        let by_name = LobbyFilter {
+            sort: LobbySort::Name,
+            ..Default::default()
+        };
+        assert_eq!(ids(registry.list(&by_name, page)), ["BBBBB", "AAAAA", "CCCCC"]);
+
+        let on_dust = LobbyFilter {
+            equals: BTreeMap::from([("map".to_string(), "dust".to_string())]),
+            hide_full: true,
+            ..Default::default()
+        };
+        assert_eq!(ids(registry.list(&on_dust, page)), ["AAAAA"]);
+
+        //Ties stay in code order across pages
+        let fewest = LobbyFilter {
+            sort: LobbySort::FewestPlayers,
+            ..Default::default()
+        };
+        let (second, total) = registry.list(&fewest, LobbyPage { index: 1, size: 2 });
+        assert_eq!(total, 3);
+        assert_eq!(ids((second, total)), ["BBBBB"]);
+        assert_eq!(ids(registry.list(&fewest, LobbyPage { index: 0, size: 2 })), ["AAAAA", "CCCCC"]);
+    }
+
+    #[test]
+    fn codes_are_unique_until_exhausted() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let generator = AlphabetCodeGenerator {
+            length: 1,
+            alphabet: "ab".to_string(),
+            exclude_ambiguous: false,
+        };
+
+        let mut registry = LobbyRegistry::default();
+        for host_id in 0..2 {
+            let code = registry.unused_code(&generator, &mut rng).unwrap();
+            registry.insert(code, host_id, addr(1000), NatType::Unknown, Vec::new(), LobbyInfo::default());
+        }
+
+        assert!(registry.contains("A") && registry.contains("B"));
+        assert_eq!(registry.unused_code(&generator, &mut rng), None);
+    }
+
+    #[test]
+    fn ambiguous_characters_are_left_out() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let generator = AlphabetCodeGenerator {
+            exclude_ambiguous: true,
+            ..Default::default()
+        };
+
+        assert_eq!(generator.charset().len(), 31);
+        for _ in 0..100 {
+            let code = generator.generate(&mut rng);
+            assert_eq!(code.len(), 5);
+            assert!(!code.chars().any(|c| AMBIGUOUS_CHARS.contains(c)));
+        }
+    }
+
+    #[test]
+    fn word_codes_normalize_player_input() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let generator = WordCodeGenerator::default();
+
+        let code = generator.generate(&mut rng);
+        assert_eq!(code.split('-').count(), 3);
+        assert_eq!(generator.normalize(&code.to_lowercase().replace('-', " ")), code);
+        assert_eq!(generator.normalize(" blue fox-42 "), "BLUE-FOX-42");
+    }
+}
@@ -1,13 +1,162 @@
-use bevy::{prelude::*, log::LogPlugin};
-use bevy_punchthrough::server::PunchThroughServerPlugin;
+use std::{path::PathBuf, process};
 
+use bevy::{
+    log::{LogPlugin, LogSettings},
+    prelude::*,
+};
+use bevy_punchthrough::{
+    auth::{parse_private_key, ServerAuth, PRIVATE_KEY_ENV},
+    server::{PunchThroughServerPlugin, ServerBind},
+    settings::{ServerSettings, SettingsOverrides},
+};
+use clap::Parser;
+
+/// Rendezvous server that introduces bevy_punchthrough clients to each other.
+/// Settings come from the --config file when one is given, flags override it
+#[derive(Parser, Debug)]
+#[clap(version, about)]
+struct Args {
+    /// TOML settings file. It is watched while the server runs and safe changes are applied without a restart
+    #[clap(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Address to listen on, as ADDR or ADDR=PUBLIC_ADDR when clients reach it through another address.
+    /// Repeat to listen on several, such as one address of each family [default: 127.0.0.1:5000 and [::1]:5000]
+    #[clap(long = "bind", value_name = "ADDR[=PUBLIC_ADDR]")]
+    binds: Vec<ServerBind>,
+
+    /// Relay datagrams for peers that can't punch through on this address, as ADDR or ADDR=PUBLIC_ADDR
+    #[clap(long, value_name = "ADDR[=PUBLIC_ADDR]")]
+    relay: Option<ServerBind>,
+
+    /// Connected clients allowed on each bind address [default: 64]
+    #[clap(long)]
+    max_clients: Option<usize>,
+
+    /// Must match the protocol id the clients were built with
+    #[clap(long)]
+    protocol_id: Option<u64>,
+
+    /// Hex encoded netcode private key. Without one the server accepts unauthenticated clients
+    #[clap(long, env = PRIVATE_KEY_ENV, hide_env_values = true)]
+    private_key: Option<String>,
+
+    /// One of trace, debug, info, warn or error [default: info]
+    #[clap(long)]
+    log_level: Option<String>,
+
+    /// Extra tracing filter directives, e.g. "bevy_punchthrough=debug"
+    #[clap(long)]
+    log_filter: Option<String>,
+
+    /// Hand out word codes like BLUE-FOX-42 instead of random characters
+    #[clap(long)]
+    word_codes: bool,
+
+    /// Length of character lobby codes [default: 5]
+    #[clap(long, conflicts_with = "word-codes")]
+    code_length: Option<usize>,
+
+    /// Characters lobby codes are drawn from
+    #[clap(long, conflicts_with = "word-codes")]
+    code_alphabet: Option<String>,
+
+    /// Leave easily confused characters such as 0 and O out of lobby codes
+    #[clap(long, conflicts_with = "word-codes")]
+    exclude_ambiguous: bool,
+
+    /// Milliseconds a client with an incompatible protocol version stays connected to learn why it is rejected
+    #[clap(long)]
+    rejected_client_grace_ms: Option<u64>,
+
+    /// Milliseconds between keepalive packets on idle connections
+    #[clap(long)]
+    heartbeat_ms: Option<u64>,
+
+    /// Validate the settings and exit without starting the server
+    #[clap(long)]
+    check_config: bool,
+}
+
+impl Args {
+    fn server_plugin(&self) -> Result<PunchThroughServerPlugin, Box<dyn std::error::Error>> {
+        let mut settings = match &self.config {
+            Some(path) => ServerSettings::load(path)?,
+            None => ServerSettings::default(),
+        };
+        let overrides = self.overrides();
+        overrides.apply(&mut settings);
+
+        let authentication = match &self.private_key {
+            Some(hex) => ServerAuth::Secure {
+                private_key: parse_private_key(hex)?,
+            },
+            None => ServerAuth::Unsecure,
+        };
+
+        let plugin = PunchThroughServerPlugin {
+            settings,
+            settings_file: self.config.clone(),
+            settings_overrides: overrides,
+            lobby_codes: None,
+            authentication,
+        };
+        plugin.validate()?;
+
+        Ok(plugin)
+    }
+
+    /// Every flag that overrides the settings file
+    fn overrides(&self) -> SettingsOverrides {
+        SettingsOverrides {
+            binds: self.binds.clone(),
+            relay: self.relay,
+            max_clients: self.max_clients,
+            protocol_id: self.protocol_id,
+            log_level: self.log_level.clone(),
+            log_filter: self.log_filter.clone(),
+            word_codes: self.word_codes,
+            code_length: self.code_length,
+            code_alphabet: self.code_alphabet.clone(),
+            exclude_ambiguous: self.exclude_ambiguous,
+            rejected_client_grace_ms: self.rejected_client_grace_ms,
+            heartbeat_ms: self.heartbeat_ms,
+        }
+    }
+}
 
 fn main(){
+    let args = Args::parse();
+    let plugin = match args.server_plugin() {
+        Ok(plugin) => plugin,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            process::exit(2);
+        }
+    };
+
+    if args.check_config {
+        println!("Configuration is valid");
+        for bind in plugin.settings.binds.iter() {
+            println!("  listening on {} reachable at {}", bind.addr, bind.public_addr());
+        }
+        if let Some(relay) = plugin.settings.relay.bind {
+            println!("  relaying on {} reachable at {}", relay.addr, relay.public_addr());
+        }
+        println!("  authentication: {:?}", plugin.authentication);
+        return;
+    }
+
     let mut app = bevy::app::App::new();
 
+    let log = &plugin.settings.log;
+    app.insert_resource(LogSettings {
+        level: log.level().expect("Log level was validated"),
+        filter: log.filter.clone(),
+    });
     app.add_plugins(MinimalPlugins);
     app.add_plugin(LogPlugin);
-    app.add_plugin(PunchThroughServerPlugin{port: 5000});
+    app.add_plugin(plugin);
     app.add_startup_system(server_start);
 
     app.run();
@@ -15,4 +164,28 @@ fn main(){
 
 fn server_start(){
     info!("PunchThrough Server Starting")
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn cli_is_well_formed() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn binds_take_public_addresses() {
+        let args = Args::parse_from(["punchthrough", "--bind", "0.0.0.0:5000=203.0.113.7:5000", "--bind", "[::1]:5001"]);
+        let plugin = args.server_plugin().unwrap();
+
+        assert_eq!(plugin.settings.binds[0].public_addr(), "203.0.113.7:5000".parse().unwrap());
+        assert_eq!(plugin.settings.binds[1].public_addr(), "[::1]:5001".parse().unwrap());
+
+        let unreachable = Args::parse_from(["punchthrough", "--bind", "0.0.0.0:5000"]);
+        assert!(unreachable.server_plugin().is_err());
+    }
+}
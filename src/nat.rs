@@ -0,0 +1,537 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// How long a client waits for mapping echoes before classifying its NAT with what it has
+pub const NAT_PROBE_TIMEOUT: Duration = Duration::from_millis(1500);
+
+/// Largest step between ports still treated as sequential allocation. Random ports are rarely this close together
+pub const MAX_SEQUENTIAL_DELTA: i32 = 16;
+
+/// How a NAT picks the external port for each new destination a client sends to
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortAllocation {
+    /// Every destination sees the same external port, so the address the server saw is all a peer needs
+    EndpointIndependent,
+    /// Each new destination gets the previous port plus delta, typical of symmetric NATs on hotspots and CGNAT
+    Sequential { delta: i32 },
+    /// No pattern, the port used towards a peer can't be predicted
+    Random,
+}
+
+impl PortAllocation {
+    /// Infers the pattern from the external ports seen for one client socket, in the order the mappings were created.
+    /// The first port is the one the rendezvous server saw, which may have been allocated long before the others,
+    /// so it only counts towards the delta when there are no other two ports to compare
+    pub fn infer(ports: &[u16]) -> Option<Self> {
+        if ports.len() < 2 {
+            return None;
+        }
+        if ports.iter().all(|port| *port == ports[0]) {
+            return Some(Self::EndpointIndependent);
+        }
+
+        let recent = if ports.len() > 2 { &ports[1..] } else { ports };
+        let deltas: Vec<i32> = recent.windows(2).map(|pair| pair[1] as i32 - pair[0] as i32).collect();
+        match deltas[0] {
+            0 => Some(Self::Random),
+            delta if delta.abs() <= MAX_SEQUENTIAL_DELTA && deltas.iter().all(|d| *d == delta) => {
+                Some(Self::Sequential { delta })
+            }
+            _ => Some(Self::Random),
+        }
+    }
+}
+
+/// What the server has learned about how a client's NAT maps its socket. Addresses are kept canonical, a dual stack
+/// socket sees the same IPv4 client mapped into IPv6
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientMapping {
+    /// Sent back by the client in its mapping probes so observers can tell who they are from
+    pub token: u64,
+    /// Public address of the client as seen by the renet socket
+    pub server_observed: SocketAddr,
+    /// As seen by each observer socket, in the order the client probes them
+    pub observer_observed: Vec<Option<SocketAddr>>,
+}
+
+impl ClientMapping {
+    pub fn new(token: u64, server_observed: SocketAddr, observers: usize) -> Self {
+        Self {
+            token,
+            server_observed: canonical(server_observed),
+            observer_observed: vec![None; observers],
+        }
+    }
+
+    /// Keeps the first address seen by each observer
+    pub fn record(&mut self, observer: usize, from: SocketAddr) {
+        if let Some(observed @ None) = self.observer_observed.get_mut(observer) {
+            *observed = Some(canonical(from));
+        }
+    }
+
+    /// None until every observer has heard from the client
+    pub fn allocation(&self) -> Option<PortAllocation> {
+        let observed: Option<Vec<SocketAddr>> = self.observer_observed.iter().copied().collect();
+        let observed = observed?;
+
+        // A NAT that also changes the external IP per destination can't be predicted by port alone
+        if observed.iter().any(|addr| addr.ip() != self.server_observed.ip()) {
+            return Some(PortAllocation::Random);
+        }
+
+        let ports: Vec<u16> = std::iter::once(self.server_observed.port())
+            .chain(observed.iter().map(|addr| addr.port()))
+            .collect();
+        PortAllocation::infer(&ports)
+    }
+
+    /// Addresses, besides server_observed, the client's next mapping is likely to use.
+    /// range is how many allocations ahead to look, to allow for other traffic the client sends in between
+    pub fn predicted_addrs(&self, range: u16) -> Vec<SocketAddr> {
+        let delta = match self.allocation() {
+            Some(PortAllocation::Sequential { delta }) => delta,
+            _ => return Vec::new(),
+        };
+        let last_port = match self.observer_observed.last() {
+            Some(Some(addr)) => addr.port() as i32,
+            _ => return Vec::new(),
+        };
+
+        (1..=range as i32)
+            .map(|step| last_port + delta * step)
+            .filter(|port| (1..=u16::MAX as i32).contains(port))
+            .map(|port| SocketAddr::new(self.server_observed.ip(), port as u16))
+            .collect()
+    }
+}
+
+/// The kind of NAT between a client and the internet, in the classic STUN terms
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NatType {
+    /// Not classified yet, or the rendezvous server has no observers to classify against
+    Unknown,
+    /// No translation, the socket is directly reachable
+    Open,
+    /// One mapping per socket that accepts packets from anyone
+    FullCone,
+    /// One mapping per socket that accepts packets from any port of an IP we have sent to
+    Restricted,
+    /// One mapping per socket that only accepts packets from the exact addresses we have sent to
+    PortRestricted,
+    /// A new mapping per destination
+    Symmetric,
+    /// Nothing came back from the rendezvous server's observers
+    UdpBlocked,
+}
+
+/// Which socket the rendezvous server answered a mapping probe from
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EchoPath {
+    /// The observer the probe was sent to
+    Direct,
+    /// A port on the observer's IP the client never sent to. Getting through rules out port restricted filtering
+    ChangedPort,
+    /// A second IP the client never sent to. Getting through means anyone can reach the mapping
+    ChangedIp,
+}
+
+/// How the server expects two clients to get connected, going by their NAT types
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectStrategy {
+    /// Plain hole punching to each other's observed address
+    Direct,
+    /// Punching that relies on predicted ports for a symmetric NAT
+    PredictedPort,
+    /// Punching is very unlikely to work, the clients need a relay
+    Relay,
+}
+
+impl ConnectStrategy {
+    pub fn between(a: NatType, b: NatType) -> Self {
+        use NatType::*;
+
+        match (a, b) {
+            (UdpBlocked, _) | (_, UdpBlocked) | (Symmetric, Symmetric) => Self::Relay,
+            // A peer that accepts packets from anyone can simply wait for the symmetric side to reach it
+            (Symmetric, Open | FullCone) | (Open | FullCone, Symmetric) => Self::Direct,
+            (Symmetric, _) | (_, Symmetric) => Self::PredictedPort,
+            _ => Self::Direct,
+        }
+    }
+}
+
+/// A NAT classification in progress on the client, fed by the echoes to its mapping probes
+#[derive(Debug, Clone)]
+pub struct NatProbe {
+    pub token: u64,
+    pub started_at: Duration,
+    /// The punch socket's address on the local interface that routes to the rendezvous server
+    pub local_addr: SocketAddr,
+    pub observers: Vec<SocketAddr>,
+    /// Our public address as reported by each observer
+    pub observed: Vec<Option<SocketAddr>>,
+    pub changed_port: bool,
+    pub changed_ip: bool,
+}
+
+impl NatProbe {
+    pub fn new(token: u64, local_addr: SocketAddr, observers: Vec<SocketAddr>, now: Duration) -> Self {
+        Self {
+            token,
+            started_at: now,
+            local_addr,
+            observed: vec![None; observers.len()],
+            observers,
+            changed_port: false,
+            changed_ip: false,
+        }
+    }
+
+    pub fn on_echo(&mut self, from: SocketAddr, token: u64, observed: SocketAddr, path: EchoPath) {
+        if token != self.token {
+            return;
+        }
+
+        match path {
+            EchoPath::Direct => {
+                if let Some(index) = self.observers.iter().position(|observer| *observer == from) {
+                    self.observed[index] = Some(observed);
+                }
+            }
+            EchoPath::ChangedPort => self.changed_port = true,
+            EchoPath::ChangedIp => self.changed_ip = true,
+        }
+    }
+
+    /// Filtering can only be ruled out by waiting, so the probe always runs until the timeout unless every echo
+    /// that could arrive has
+    pub fn done(&self, now: Duration) -> bool {
+        let complete = self.changed_ip && self.observed.iter().all(Option::is_some);
+        complete || now.saturating_sub(self.started_at) >= NAT_PROBE_TIMEOUT
+    }
+
+    /// Without an alternate IP on the server a full cone NAT is reported as Restricted
+    pub fn classify(&self) -> NatType {
+        let observed: Vec<SocketAddr> = self.observed.iter().flatten().copied().collect();
+        let first = match observed.first() {
+            Some(first) => *first,
+            None => return NatType::UdpBlocked,
+        };
+
+        if observed.iter().any(|addr| *addr != first) {
+            NatType::Symmetric
+        } else if first == self.local_addr {
+            NatType::Open
+        } else if self.changed_ip {
+            NatType::FullCone
+        } else if self.changed_port {
+            NatType::Restricted
+        } else {
+            NatType::PortRestricted
+        }
+    }
+}
+
+/// Which IP version an address belongs to. Punching is tried over IPv6 first when both peers have global IPv6
+/// addresses, since there is usually no NAT in the way
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    /// IPv4 addresses mapped into IPv6 count as V4
+    pub fn of(addr: SocketAddr) -> Self {
+        match canonical(addr) {
+            SocketAddr::V4(_) => Self::V4,
+            SocketAddr::V6(_) => Self::V6,
+        }
+    }
+
+    /// A documentation address of this family, only used to ask the OS which interface routes to it
+    fn route_probe(self) -> SocketAddr {
+        match self {
+            Self::V4 => (Ipv4Addr::new(192, 0, 2, 1), 9).into(),
+            Self::V6 => (Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 9).into(),
+        }
+    }
+}
+
+/// Dual stack sockets see IPv4 peers as IPv4-mapped IPv6 addresses, this turns them back into plain IPv4 so they
+/// compare equal to the candidates the server handed out
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// addr in the form a socket bound to local can send to, IPv6 sockets need IPv4 destinations mapped into IPv6
+pub fn sendable(addr: SocketAddr, local: SocketAddr) -> SocketAddr {
+    match (addr, local) {
+        (SocketAddr::V4(v4), SocketAddr::V6(_)) => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+        _ => addr,
+    }
+}
+
+/// Whether ip is an IPv6 address reachable from the internet, rather than loopback, link local, unique local,
+/// multicast, documentation or an IPv4 address in disguise
+pub fn is_global_v6(ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V6(ip) => ip,
+        IpAddr::V4(_) => return false,
+    };
+    let first = ip.segments()[0];
+    let special = ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || ip.to_ipv4_mapped().is_some()
+        || first & 0xffc0 == 0xfe80
+        || first & 0xfe00 == 0xfc00
+        || (first, ip.segments()[1]) == (0x2001, 0xdb8);
+    !special
+}
+
+/// The address of the local interface packets to destination leave from. Connecting a UDP socket sends nothing,
+/// it only makes the OS pick a route
+pub fn local_ip_towards(destination: SocketAddr) -> io::Result<IpAddr> {
+    let unspecified: SocketAddr = match destination {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(unspecified)?;
+    socket.connect(destination)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// Addresses a peer on our own network could reach the socket bound to bound on, tried alongside the public one in
+/// case the router doesn't hairpin. That's bound itself when it is a specific address, otherwise the interface that
+/// routes to server. Sockets bound to the unspecified IPv6 address are dual stack, so they get an address of each
+/// family, IPv6 first
+pub fn local_candidates(bound: SocketAddr, server: SocketAddr) -> Vec<SocketAddr> {
+    if !bound.ip().is_unspecified() {
+        return vec![bound];
+    }
+
+    let families = match bound {
+        SocketAddr::V4(_) => vec![IpFamily::V4],
+        SocketAddr::V6(_) => vec![IpFamily::V6, IpFamily::V4],
+    };
+    families
+        .into_iter()
+        .filter_map(|family| {
+            let server = canonical(server);
+            let destination = match IpFamily::of(server) == family {
+                true => server,
+                false => family.route_probe(),
+            };
+            local_ip_towards(destination).ok()
+        })
+        .map(|ip| SocketAddr::new(ip, bound.port()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    /// Behaviour of a SimulatedNat
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum NatBehavior {
+        /// One external port per internal socket, whatever the destination
+        EndpointIndependent,
+        /// A new external port per destination, each delta after the last
+        Symmetric { delta: u16 },
+        /// A new random external port per destination
+        Random,
+    }
+
+    /// An in-process model of a NAT's port allocation, for testing prediction without real network equipment
+    #[derive(Debug)]
+    struct SimulatedNat {
+        public_ip: IpAddr,
+        behavior: NatBehavior,
+        next_port: u16,
+        mappings: HashMap<(SocketAddr, Option<SocketAddr>), u16>,
+        rng: StdRng,
+    }
+
+    impl SimulatedNat {
+        fn new(public_ip: IpAddr, behavior: NatBehavior, first_port: u16) -> Self {
+            Self {
+                public_ip,
+                behavior,
+                next_port: first_port,
+                mappings: HashMap::new(),
+                rng: StdRng::seed_from_u64(first_port as u64),
+            }
+        }
+
+        /// The external address a packet from internal to destination leaves with, creating the mapping if needed
+        fn map(&mut self, internal: SocketAddr, destination: SocketAddr) -> SocketAddr {
+            let key = match self.behavior {
+                NatBehavior::EndpointIndependent => (internal, None),
+                NatBehavior::Symmetric { .. } | NatBehavior::Random => (internal, Some(destination)),
+            };
+
+            let port = match self.mappings.get(&key) {
+                Some(port) => *port,
+                None => {
+                    let port = match self.behavior {
+                        NatBehavior::Random => self.rng.gen_range(1024..=u16::MAX),
+                        NatBehavior::EndpointIndependent | NatBehavior::Symmetric { .. } => self.next_port,
+                    };
+                    if let NatBehavior::Symmetric { delta } = self.behavior {
+                        self.next_port = self.next_port.wrapping_add(delta);
+                    }
+                    self.mappings.insert(key, port);
+                    port
+                }
+            };
+
+            SocketAddr::new(self.public_ip, port)
+        }
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    /// Runs a client behind the NAT through the observation the rendezvous server does, then opens a mapping
+    /// towards a peer. Returns the mapping the server inferred and the address the peer actually has to reach
+    fn observe_then_punch(behavior: NatBehavior) -> (ClientMapping, SocketAddr) {
+        let mut nat = SimulatedNat::new(IpAddr::from([198, 51, 100, 4]), behavior, 40000);
+        let client = SocketAddr::from(([192, 168, 1, 20], 5555));
+        let observers = [addr(5001), addr(5002)];
+
+        let mut mapping = ClientMapping::new(7, nat.map(client, addr(5000)), observers.len());
+        for (index, observer) in observers.iter().enumerate() {
+            mapping.record(index, nat.map(client, *observer));
+        }
+
+        // Someone else's traffic through the same NAT in between
+        nat.map(SocketAddr::from(([192, 168, 1, 21], 6000)), addr(80));
+
+        let towards_peer = nat.map(client, SocketAddr::from(([203, 0, 113, 9], 7000)));
+        (mapping, towards_peer)
+    }
+
+    #[test]
+    fn sequential_ports_are_predicted() {
+        let (mapping, towards_peer) = observe_then_punch(NatBehavior::Symmetric { delta: 2 });
+
+        assert_eq!(mapping.allocation(), Some(PortAllocation::Sequential { delta: 2 }));
+        assert_ne!(towards_peer, mapping.server_observed);
+        assert!(mapping.predicted_addrs(4).contains(&towards_peer));
+    }
+
+    #[test]
+    fn cone_and_random_nats_get_no_predictions() {
+        let (mapping, towards_peer) = observe_then_punch(NatBehavior::EndpointIndependent);
+        assert_eq!(mapping.allocation(), Some(PortAllocation::EndpointIndependent));
+        assert_eq!(towards_peer, mapping.server_observed);
+        assert!(mapping.predicted_addrs(4).is_empty());
+
+        let (mapping, _) = observe_then_punch(NatBehavior::Random);
+        assert_eq!(mapping.allocation(), Some(PortAllocation::Random));
+        assert!(mapping.predicted_addrs(4).is_empty());
+    }
+
+    #[test]
+    fn nat_types_are_classified_from_echoes() {
+        let local = SocketAddr::from(([192, 168, 1, 20], 5555));
+        let public = SocketAddr::from(([198, 51, 100, 4], 40000));
+        let observers = vec![addr(5001), addr(5002)];
+        let probe = |observed: [SocketAddr; 2], changed_port: bool, changed_ip: bool| {
+            let mut probe = NatProbe::new(7, local, observers.clone(), Duration::ZERO);
+            for (observer, observed) in observers.iter().zip(observed) {
+                probe.on_echo(*observer, 7, observed, EchoPath::Direct);
+            }
+            probe.changed_port = changed_port;
+            probe.changed_ip = changed_ip;
+            probe.classify()
+        };
+
+        assert_eq!(probe([local, local], true, true), NatType::Open);
+        assert_eq!(probe([public, public], true, true), NatType::FullCone);
+        assert_eq!(probe([public, public], true, false), NatType::Restricted);
+        assert_eq!(probe([public, public], false, false), NatType::PortRestricted);
+        assert_eq!(probe([public, SocketAddr::new(public.ip(), 40002)], false, false), NatType::Symmetric);
+
+        let mut silent = NatProbe::new(7, local, observers.clone(), Duration::ZERO);
+        silent.on_echo(observers[0], 8, public, EchoPath::Direct);
+        assert!(!silent.done(NAT_PROBE_TIMEOUT - Duration::from_millis(1)));
+        assert!(silent.done(NAT_PROBE_TIMEOUT));
+        assert_eq!(silent.classify(), NatType::UdpBlocked);
+    }
+
+    #[test]
+    fn strategy_follows_nat_types() {
+        use NatType::*;
+
+        assert_eq!(ConnectStrategy::between(PortRestricted, Restricted), ConnectStrategy::Direct);
+        assert_eq!(ConnectStrategy::between(Symmetric, FullCone), ConnectStrategy::Direct);
+        assert_eq!(ConnectStrategy::between(PortRestricted, Symmetric), ConnectStrategy::PredictedPort);
+        assert_eq!(ConnectStrategy::between(Symmetric, Symmetric), ConnectStrategy::Relay);
+        assert_eq!(ConnectStrategy::between(Open, UdpBlocked), ConnectStrategy::Relay);
+    }
+
+    #[test]
+    fn allocation_waits_for_every_observer() {
+        let mut mapping = ClientMapping::new(7, addr(40000), 2);
+        mapping.record(0, addr(40001));
+        assert_eq!(mapping.allocation(), None);
+
+        mapping.record(1, addr(40002));
+        mapping.record(1, addr(40009));
+        assert_eq!(mapping.allocation(), Some(PortAllocation::Sequential { delta: 1 }));
+    }
+
+    #[test]
+    fn dual_stack_observers_see_one_mapping() {
+        let mapped = |port| SocketAddr::new("::ffff:203.0.113.7".parse().unwrap(), port);
+        let v4 = |port| SocketAddr::from(([203, 0, 113, 7], port));
+
+        let mut mapping = ClientMapping::new(7, mapped(40000), 2);
+        mapping.record(0, v4(40000));
+        mapping.record(1, mapped(40000));
+        assert_eq!(mapping.allocation(), Some(PortAllocation::EndpointIndependent));
+
+        let mut mapping = ClientMapping::new(7, v4(40000), 2);
+        mapping.record(0, mapped(40001));
+        mapping.record(1, v4(40002));
+        assert_eq!(mapping.allocation(), Some(PortAllocation::Sequential { delta: 1 }));
+        assert_eq!(mapping.predicted_addrs(1), [v4(40003)]);
+    }
+
+    #[test]
+    fn mapped_addresses_are_v4() {
+        let v4: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:203.0.113.7]:40000".parse().unwrap();
+        let dual_stack: SocketAddr = "[::]:50000".parse().unwrap();
+
+        assert_eq!(canonical(mapped), v4);
+        assert_eq!(IpFamily::of(mapped), IpFamily::V4);
+        assert_eq!(sendable(v4, dual_stack), mapped);
+        assert_eq!(sendable(v4, addr(50000)), v4);
+
+        assert!(is_global_v6("2606:4700::1111".parse().unwrap()));
+        for ip in ["::1", "fe80::1", "fd00::1", "2001:db8::1", "::ffff:203.0.113.7", "203.0.113.7"] {
+            assert!(!is_global_v6(ip.parse().unwrap()), "{ip}");
+        }
+    }
+}
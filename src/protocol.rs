@@ -0,0 +1,303 @@
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    candidate::{Candidate, Role},
+    lobby::{LobbyFilter, LobbyInfo, LobbyListing, LobbyPage},
+    nat::{ConnectStrategy, NatType},
+};
+
+/// Version of the messages in this module. Bump it whenever their encoding changes
+pub const PROTOCOL_VERSION: u32 = 14;
+
+/// Optional features a peer supports, exchanged in the Hello handshake
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    /// Answers ObserveMapping, so peers can be given predicted ports for it
+    pub const PORT_PREDICTION: Self = Self(1);
+    /// Falls back to RequestRelay when a punch times out
+    pub const RELAY: Self = Self(2);
+
+    /// Everything this build supports
+    pub const SUPPORTED: Self = Self(Self::PORT_PREDICTION.0 | Self::RELAY.0);
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Features both sides support
+    pub fn intersection(&self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// The first message a client sends once connected. Nothing else is accepted from it until the server welcomes it.
+/// The encoding of Hello and HelloResponse is frozen so that any two builds can at least tell each other they are
+/// incompatible. Both travel on the Handshake channels, where nothing else is sent
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    pub fn current() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HelloResponse {
+    Welcome { protocol_version: u32, capabilities: Capabilities },
+    /// The server is about to disconnect the client
+    Incompatible { server: u32, client: u32 },
+}
+
+impl HelloResponse {
+    /// What the server running this build answers to a Hello
+    pub fn answer(hello: &Hello) -> Self {
+        match hello.protocol_version == PROTOCOL_VERSION {
+            true => Self::Welcome {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: Capabilities::SUPPORTED,
+            },
+            false => Self::Incompatible {
+                server: PROTOCOL_VERSION,
+                client: hello.protocol_version,
+            },
+        }
+    }
+}
+
+/// Chosen by the client for each request and echoed back by the server on every response to it
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RequestId(pub u32);
+
+/// Envelope for everything a client sends to the server
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClientMessage {
+    pub request_id: RequestId,
+    pub body: ClientToServer,
+}
+
+/// Envelope for everything the server sends to a client.
+/// request_id is set when the message answers a request, and None for notifications the server sends on its own
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ServerMessage {
+    pub request_id: Option<RequestId>,
+    pub body: ServerToClient,
+}
+
+impl ServerMessage {
+    pub fn response(request_id: RequestId, body: ServerToClient) -> Self {
+        Self {
+            request_id: Some(request_id),
+            body,
+        }
+    }
+
+    pub fn notification(body: ServerToClient) -> Self {
+        Self { request_id: None, body }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum ClientToServer {
+    /// Informs the server that the application at this address would like to allow punch through connections.
+    /// Server will store this info and make it available for RequestSwap until it receives the disconnect event.
+    /// local_addrs are our addresses on the local network, for peers behind the same NAT. info is handed to joiners
+    HostNewLobby { nat_type: NatType, local_addrs: Vec<SocketAddr>, info: LobbyInfo },
+    /// Replaces what we publish about the lobby we host, answered with LobbyUpdated
+    UpdateLobby { info: LobbyInfo },
+    RequestSwap { lobby_id: String, nat_type: NatType, local_addrs: Vec<SocketAddr> },
+    /// Asks for a page of the public lobbies matching filter, answered with LobbyList
+    ListLobbies { filter: LobbyFilter, page: LobbyPage },
+    /// Asks the server to forward datagrams to the peer at this address, after punching towards it failed
+    RequestRelay { peer: SocketAddr },
+    /// Sent every KeepaliveSchedule::server_interval while hosting, so a NAT that drops idle mappings keeps the one
+    /// joiners are told to punch towards. Never answered and not rate limited
+    Keepalive,
+    /// Sent from a new socket right after the Hello, to carry on the session client_id had before we rebound.
+    /// Its lobby moves over to this connection and the server answers with AddressChanged
+    Resume { client_id: u64, resume_token: u64 },
+    /// Sent when punching towards the peer failed for good, so a lobby we were joining stops counting us towards its
+    /// players. Hosts keep their lobby. Never answered and not rate limited
+    LeaveLobby,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum ServerToClient {
+    NewLobbyResponse { lobby_id: String },
+    /// strategy is how the server expects us to reach the host, given both NAT types. info is what the host published
+    JoinLobbyResponse { lobby_id: String, strategy: ConnectStrategy, info: LobbyInfo },
+    LobbyUpdated { lobby_id: String },
+    /// page is the one requested, its size cut down to what the server allows. total counts every matching lobby
+    LobbyList { lobbies: Vec<LobbyListing>, page: LobbyPage, total: u32 },
+    /// Sent to a host when another client joins its lobby, just before the matching AttemptHandshakeCommand
+    PeerJoining { lobby_id: String, peer_id: u64 },
+    /// Tells a client to punch towards its counterpart in a lobby, checking every pair of local (our candidates as the
+    /// server gathered them) and remote (the counterpart's). peer is the counterpart's public address as the server
+    /// sees it. The host is controlling and the joiner controlled
+    AttemptHandshakeCommand { peer: SocketAddr, role: Role, local: Vec<Candidate>, remote: Vec<Candidate> },
+    /// Asks the client to send a PunchPacket::MappingProbe carrying token to each observer, in order
+    ObserveMapping { token: u64, observers: Vec<SocketAddr> },
+    /// Sent to clients joining a lobby when its host disconnects or replaces it
+    LobbyClosed { lobby_id: String },
+    /// The request could not be carried out
+    Error { err: ClientError },
+    /// Message of the day from the server operator, sent after the Hello handshake and whenever it changes
+    Motd { message: String },
+    /// Sent to both peers once the server agrees to relay between them. Datagrams for the peer go to relay wrapped in
+    /// a PunchPacket::Relayed carrying token
+    RelayReady { relay: SocketAddr, token: u64, peer: SocketAddr },
+    /// The relay session using token ran out of time or the peer disconnected
+    RelayClosed { token: u64 },
+    /// Sent once the Hello handshake is done. observed is our public address as the server sees it, resume_token lets
+    /// us carry this session over to another socket, see ClientToServer::Resume. address_check is only set when the
+    /// server has a NAT observer to notice our address moving with
+    Session { observed: SocketAddr, resume_token: u64, address_check: Option<AddressCheck> },
+    /// Answers Resume, the server now sees us at observed rather than previous and points joiners of our lobby there
+    AddressChanged { previous: SocketAddr, observed: SocketAddr },
+}
+
+/// Where a host sends PunchPacket::AddressKeepalive alongside its keepalives to the server. Netcode drops traffic from
+/// an address it doesn't know, the observer still tells the client apart by token after its public address moved
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressCheck {
+    pub observer: SocketAddr,
+    pub token: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ClientError {
+    LobbyNotFound { lobby: String },
+    /// The server could not find a lobby code that isn't already in use
+    LobbyCodesExhausted,
+    InternalServerError,
+    Protocol(ProtocolError),
+    /// Client and server were built against different protocol versions
+    IncompatibleVersion { server: u32, client: u32 },
+    /// The server already has as many lobbies open as it is configured to allow
+    LobbyLimitReached,
+    /// Too many requests were sent too quickly, try again shortly
+    RateLimited,
+    /// The server doesn't relay, or is already relaying as many sessions as it allows
+    RelayUnavailable,
+    /// Relays are only set up between clients in the same lobby
+    PeerNotFound { peer: SocketAddr },
+    /// The session being resumed ended already or the resume token doesn't match it
+    SessionNotFound,
+    /// The lobby already has as many players as its host allows
+    LobbyFull { lobby: String },
+    /// The lobby info carries more text than the server accepts
+    LobbyInfoTooLarge { max_bytes: usize },
+}
+
+/// The peer broke the protocol rather than asking for something that failed
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProtocolError {
+    /// The bytes received could not be decoded as a message
+    Malformed,
+    /// The message is well formed but not allowed for the role the sender currently has
+    InvalidForRole { role: ClientRole, message: String },
+}
+
+/// What a connected client is currently doing on the server
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ClientRole {
+    Idle,
+    Host,
+    Joiner,
+}
+
+impl ClientRole {
+    /// Whether a client in this role may send the given request
+    pub fn allows(&self, request: &ClientToServer) -> bool {
+        match (self, request) {
+            // A host has to close its lobby (by disconnecting or hosting again) before it joins someone else's
+            (Self::Host, ClientToServer::RequestSwap { .. }) => false,
+            // Only a host has a lobby to update
+            (Self::Idle | Self::Joiner, ClientToServer::UpdateLobby { .. }) => false,
+            // Relays only go between a host and its joiners
+            (Self::Idle, ClientToServer::RequestRelay { .. }) => false,
+            // Only a fresh connection can take over another session
+            (Self::Host | Self::Joiner, ClientToServer::Resume { .. }) => false,
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mismatched_versions_are_rejected() {
+        assert!(matches!(HelloResponse::answer(&Hello::current()), HelloResponse::Welcome { .. }));
+
+        let old = Hello {
+            protocol_version: PROTOCOL_VERSION - 1,
+            capabilities: Capabilities::NONE,
+        };
+        assert_eq!(
+            HelloResponse::answer(&old),
+            HelloResponse::Incompatible {
+                server: PROTOCOL_VERSION,
+                client: PROTOCOL_VERSION - 1,
+            }
+        );
+    }
+
+    /// Hello and HelloResponse must decode the same way across every protocol version
+    #[test]
+    fn handshake_encoding_is_frozen() {
+        let hello = Hello {
+            protocol_version: 3,
+            capabilities: Capabilities(5),
+        };
+        assert_eq!(bincode::serialize(&hello).unwrap(), [3, 0, 0, 0, 5, 0, 0, 0]);
+
+        let incompatible = HelloResponse::Incompatible { server: 2, client: 1 };
+        assert_eq!(bincode::serialize(&incompatible).unwrap(), [1, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn hosts_cannot_join_lobbies() {
+        let join = ClientToServer::RequestSwap {
+            lobby_id: "ABCDE".to_string(),
+            nat_type: NatType::Unknown,
+            local_addrs: Vec::new(),
+        };
+
+        assert!(!ClientRole::Host.allows(&join));
+        assert!(ClientRole::Host.allows(&ClientToServer::HostNewLobby {
+            nat_type: NatType::Unknown,
+            local_addrs: Vec::new(),
+            info: LobbyInfo::default(),
+        }));
+        let update = ClientToServer::UpdateLobby { info: LobbyInfo::default() };
+        assert!(ClientRole::Host.allows(&update));
+        assert!(!ClientRole::Joiner.allows(&update));
+        assert!(ClientRole::Joiner.allows(&join));
+        assert!(ClientRole::Idle.allows(&join));
+
+        let relay = ClientToServer::RequestRelay { peer: "203.0.113.7:5000".parse().unwrap() };
+        assert!(!ClientRole::Idle.allows(&relay));
+        assert!(ClientRole::Host.allows(&relay));
+
+        let list = ClientToServer::ListLobbies { filter: LobbyFilter::default(), page: LobbyPage::default() };
+        assert!(ClientRole::Idle.allows(&list));
+        assert!(ClientRole::Host.allows(&list));
+
+        let resume = ClientToServer::Resume { client_id: 7, resume_token: 42 };
+        assert!(ClientRole::Idle.allows(&resume));
+        assert!(!ClientRole::Host.allows(&resume));
+    }
+}
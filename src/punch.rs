@@ -0,0 +1,540 @@
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    candidate::{Candidate, Checklist, Role},
+    nat::EchoPath,
+};
+
+/// Every punch packet starts with these bytes so they can't be confused with stray traffic
+pub const PUNCH_MAGIC: &[u8; 4] = b"BVPT";
+
+/// Large enough for any netcode packet, so draining the shared socket never truncates one meant for the server
+pub const MAX_DATAGRAM_SIZE: usize = 2048;
+
+/// Default wait between probes once the opening burst is sent
+pub const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Default time a handshake may take before it is reported as failed
+pub const PUNCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// When probes go out during a handshake, and when it is given up on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PunchSchedule {
+    /// Probes sent back to back as soon as the handshake starts. The first ones are often dropped while the NAT
+    /// mapping is created
+    pub burst: u32,
+    /// Wait before the first probe after the burst
+    pub interval: Duration,
+    /// Each later wait is the previous one multiplied by this. 1.0 probes at a steady interval
+    pub backoff: f32,
+    /// Waits never grow past this
+    pub max_interval: Duration,
+    /// The handshake fails if it isn't confirmed this long after it started
+    pub deadline: Duration,
+    /// No more probes are sent after this many. The handshake fails if the last one goes unanswered
+    pub max_probes: u32,
+}
+
+impl Default for PunchSchedule {
+    fn default() -> Self {
+        Self {
+            burst: 3,
+            interval: PROBE_INTERVAL,
+            backoff: 1.5,
+            max_interval: Duration::from_secs(2),
+            deadline: PUNCH_TIMEOUT,
+            max_probes: 30,
+        }
+    }
+}
+
+impl PunchSchedule {
+    /// How long to wait before the next probe once probes_sent have gone out
+    pub fn wait_after(&self, probes_sent: u32) -> Duration {
+        if probes_sent < self.burst {
+            return Duration::ZERO;
+        }
+
+        let factor = (self.backoff as f64).powi((probes_sent - self.burst) as i32);
+        if !factor.is_finite() || self.interval.as_secs_f64() * factor >= self.max_interval.as_secs_f64() {
+            return self.max_interval;
+        }
+        self.interval.mul_f64(factor.max(0.0))
+    }
+}
+
+/// How often keepalives go out so NATs that drop idle mappings after about 30 seconds keep ours open
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeepaliveSchedule {
+    /// Between keepalives to the rendezvous server while hosting, keeping the mapping the lobby points joiners at
+    pub server_interval: Duration,
+    /// Between keepalives to a punched peer
+    pub peer_interval: Duration,
+    /// A punched peer we heard no keepalive from for this long is reported unresponsive
+    pub peer_timeout: Duration,
+}
+
+impl Default for KeepaliveSchedule {
+    fn default() -> Self {
+        Self {
+            server_interval: Duration::from_secs(15),
+            peer_interval: Duration::from_secs(5),
+            peer_timeout: Duration::from_secs(20),
+        }
+    }
+}
+
+/// Which limit of the PunchSchedule ended a handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutCause {
+    Deadline,
+    ProbesExhausted,
+}
+
+/// Datagrams exchanged directly between two peers to open and confirm a NAT mapping
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum PunchPacket {
+    /// Sent repeatedly to the peer. Opens our side of the mapping and asks the peer to acknowledge.
+    /// role and tie_breaker settle role conflicts, nominate is set by the controlling side on the pair it picked
+    Probe { nonce: u64, role: Role, tie_breaker: u64, nominate: bool },
+    /// Answer to a probe. Echoes the probe nonce, whether it nominated, and the address the probe arrived from
+    Ack { nonce: u64, observed: SocketAddr, nominated: bool },
+    /// Sent to the rendezvous server's observer sockets so it can see which ports our NAT hands out
+    MappingProbe { token: u64 },
+    /// The server's answer to a MappingProbe, carrying the address the probe arrived from
+    MappingEcho { token: u64, observed: SocketAddr, path: EchoPath },
+    /// A game datagram going through the rendezvous server's relay. token identifies the relay session and side,
+    /// see ServerToClient::RelayReady. An empty payload only tells the relay where we are
+    Relayed { token: u64, payload: Vec<u8> },
+    /// Sent to a punched peer every KeepaliveSchedule::peer_interval, see PeerLink
+    Keepalive,
+    /// Sent to the rendezvous server's observer alongside every keepalive to the server while hosting, see AddressCheck
+    AddressKeepalive { token: u64 },
+    /// The observer's answer to an AddressKeepalive that came from a new address, observed is where it came from
+    AddressMoved { token: u64, observed: SocketAddr },
+}
+
+impl PunchPacket {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = PUNCH_MAGIC.to_vec();
+        bytes.extend(bincode::serialize(self).expect("Could not serialize punch packet to bytes"));
+        bytes
+    }
+
+    /// Returns None for anything that is not a well formed punch packet
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let payload = bytes.strip_prefix(PUNCH_MAGIC)?;
+        bincode::deserialize(payload).ok()
+    }
+}
+
+/// Bookkeeping for a single handshake with a peer, checking every candidate pair in its checklist.
+/// The controlling side confirms the first pair the peer acknowledges and nominates it, the controlled side confirms
+/// whichever pair is nominated. Confirmed means a full round trip went through on that pair
+#[derive(Debug, Clone)]
+pub struct PunchAttempt {
+    /// The peer's public address as the rendezvous server sees it, which identifies the peer to the server
+    pub peer: SocketAddr,
+    /// The peer's highest priority address, replaced by the nominated one once confirmed
+    pub target: SocketAddr,
+    pub checklist: Checklist,
+    pub nonce: u64,
+    pub started_at: Duration,
+    pub last_probe_at: Option<Duration>,
+    pub probes_sent: u32,
+    /// Set once a pair is nominated
+    pub confirmed: bool,
+    /// Set once the controlled side acknowledged our nomination, until then the controlling side keeps nominating
+    pub nomination_acked: bool,
+    pub schedule: PunchSchedule,
+    /// Our address as seen by each of the peer's addresses that acknowledged a probe
+    pub observed: HashMap<SocketAddr, SocketAddr>,
+}
+
+impl PunchAttempt {
+    /// An attempt checking every pair of our candidates and the peer's, see AttemptHandshakeCommand.
+    /// None when the peer gave us no address to punch towards
+    pub fn from_candidates(
+        peer: SocketAddr,
+        role: Role,
+        local: &[Candidate],
+        remote: &[Candidate],
+        now: Duration,
+        schedule: PunchSchedule,
+    ) -> Option<Self> {
+        let checklist = Checklist::new(role, rand::random(), local, remote);
+        Some(Self {
+            peer,
+            target: best_remote(&checklist, remote)?,
+            checklist,
+            nonce: rand::random(),
+            started_at: now,
+            last_probe_at: None,
+            probes_sent: 0,
+            confirmed: false,
+            nomination_acked: false,
+            schedule,
+            observed: HashMap::new(),
+        })
+    }
+
+    pub fn is_candidate(&self, addr: SocketAddr) -> bool {
+        self.checklist.contains(addr)
+    }
+
+    pub fn role(&self) -> Role {
+        self.checklist.role
+    }
+
+    /// Where the next probe goes: every pair in priority order, or only the nominated one once confirmed
+    pub fn probe_targets(&self) -> Vec<SocketAddr> {
+        match self.confirmed {
+            true => vec![self.target],
+            false => self.checklist.remote_addrs(),
+        }
+    }
+
+    /// Some once the attempt is over. A confirmed attempt only ends at the deadline, until then it keeps answering the
+    /// peer's probes
+    pub fn expired(&self, now: Duration) -> Option<TimeoutCause> {
+        if now.saturating_sub(self.started_at) >= self.schedule.deadline {
+            return Some(TimeoutCause::Deadline);
+        }
+
+        // The last probe gets at least one interval to be answered
+        let last_probe_expired = match self.last_probe_at {
+            Some(last) => {
+                let grace = self.schedule.wait_after(self.probes_sent).max(self.schedule.interval);
+                now.saturating_sub(last) >= grace
+            }
+            None => false,
+        };
+        if !self.confirmed && self.probes_sent >= self.schedule.max_probes && last_probe_expired {
+            return Some(TimeoutCause::ProbesExhausted);
+        }
+
+        None
+    }
+
+    /// True when another probe should go out. Checks stop once confirmed, nominations once acknowledged
+    pub fn probe_due(&self, now: Duration) -> bool {
+        let nominating = self.confirmed && self.role() == Role::Controlling && !self.nomination_acked;
+        if (self.confirmed && !nominating) || self.probes_sent >= self.schedule.max_probes {
+            return false;
+        }
+
+        match self.last_probe_at {
+            Some(last) => now.saturating_sub(last) >= self.schedule.wait_after(self.probes_sent),
+            None => true,
+        }
+    }
+
+    pub fn probe(&mut self, now: Duration) -> PunchPacket {
+        self.last_probe_at = Some(now);
+        self.probes_sent += 1;
+        self.checklist.on_checks_sent();
+        PunchPacket::Probe {
+            nonce: self.nonce,
+            role: self.role(),
+            tie_breaker: self.checklist.tie_breaker,
+            nominate: self.confirmed && self.role() == Role::Controlling,
+        }
+    }
+
+    /// Handles the peer's answer to one of our probes. Returns true if it confirms this handshake for the first time,
+    /// which only happens on the controlling side
+    pub fn on_ack(&mut self, from: SocketAddr, nonce: u64, nominated: bool) -> bool {
+        if !self.is_candidate(from) || nonce != self.nonce {
+            return false;
+        }
+
+        self.checklist.on_success(from);
+        if self.role() != Role::Controlling {
+            return false;
+        }
+        if self.confirmed {
+            self.nomination_acked |= nominated && from == self.target;
+            return false;
+        }
+
+        self.confirmed = true;
+        self.target = self.checklist.best_valid().map_or(from, |pair| pair.remote.addr);
+        true
+    }
+
+    /// Handles a nominating probe from the peer. Returns true if it confirms this handshake for the first time,
+    /// which only happens on the controlled side
+    pub fn on_nominated(&mut self, from: SocketAddr) -> bool {
+        if self.confirmed || self.role() != Role::Controlled || !self.is_candidate(from) {
+            return false;
+        }
+
+        self.confirmed = true;
+        self.target = from;
+        true
+    }
+}
+
+/// A punched path to a peer kept open with keepalives. A received count that stops growing while the sent count
+/// keeps climbing means the mapping died, and the game may want to rebind or register with the server again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerLink {
+    pub peer: SocketAddr,
+    pub keepalives_sent: u64,
+    pub keepalives_received: u64,
+    pub last_sent_at: Duration,
+    pub last_received_at: Duration,
+    /// Set once the peer is reported unresponsive, cleared when it is heard from again
+    pub unresponsive: bool,
+    /// Our address as the peer sees it, from its acks. Behind a NAT this is not the address we are bound to
+    pub observed: Option<SocketAddr>,
+}
+
+impl PeerLink {
+    /// Starts from a confirmed punch, which proves the path was open at now
+    pub fn new(peer: SocketAddr, now: Duration) -> Self {
+        Self {
+            peer,
+            keepalives_sent: 0,
+            keepalives_received: 0,
+            last_sent_at: now,
+            last_received_at: now,
+            unresponsive: false,
+            observed: None,
+        }
+    }
+
+    pub fn keepalive_due(&self, now: Duration, schedule: &KeepaliveSchedule) -> bool {
+        now.saturating_sub(self.last_sent_at) >= schedule.peer_interval
+    }
+
+    pub fn on_sent(&mut self, now: Duration) {
+        self.keepalives_sent += 1;
+        self.last_sent_at = now;
+    }
+
+    pub fn on_received(&mut self, now: Duration) {
+        self.keepalives_received += 1;
+        self.last_received_at = now;
+        self.unresponsive = false;
+    }
+
+    /// True the first time the peer has been silent for KeepaliveSchedule::peer_timeout
+    pub fn check_unresponsive(&mut self, now: Duration, schedule: &KeepaliveSchedule) -> bool {
+        if self.unresponsive || now.saturating_sub(self.last_received_at) < schedule.peer_timeout {
+            return false;
+        }
+        self.unresponsive = true;
+        true
+    }
+}
+
+/// The peer's highest priority address that gets probed, falling back to whatever it sent when there is none
+fn best_remote(checklist: &Checklist, remote: &[Candidate]) -> Option<SocketAddr> {
+    checklist
+        .remote_addrs()
+        .first()
+        .copied()
+        .or_else(|| remote.first().map(|candidate| candidate.addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::candidate::{gather, CandidateKind};
+
+    use super::*;
+
+    #[test]
+    fn packets_round_trip() {
+        let observed: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        for packet in [
+            PunchPacket::Probe {
+                nonce: 42,
+                role: Role::Controlled,
+                tie_breaker: 7,
+                nominate: true,
+            },
+            PunchPacket::Ack {
+                nonce: 42,
+                observed,
+                nominated: true,
+            },
+            PunchPacket::MappingProbe { token: 7 },
+            PunchPacket::MappingEcho {
+                token: 7,
+                observed,
+                path: EchoPath::ChangedPort,
+            },
+            PunchPacket::Relayed {
+                token: 7,
+                payload: b"game state".to_vec(),
+            },
+            PunchPacket::Keepalive,
+        ] {
+            assert_eq!(PunchPacket::decode(&packet.encode()), Some(packet));
+        }
+
+        assert_eq!(PunchPacket::decode(b"BevyPunchthrough Packet"), None);
+    }
+
+    /// A controlling attempt towards the peer's public address and whatever else gather adds for it
+    fn controlling(
+        peer: SocketAddr,
+        host: &[SocketAddr],
+        predicted: &[SocketAddr],
+        schedule: PunchSchedule,
+    ) -> PunchAttempt {
+        let remote = gather(host, Some(peer), predicted, None);
+        PunchAttempt::from_candidates(peer, Role::Controlling, &[], &remote, Duration::ZERO, schedule).unwrap()
+    }
+
+    #[test]
+    fn only_matching_ack_confirms() {
+        let target: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:5001".parse().unwrap();
+        let schedule = PunchSchedule {
+            burst: 1,
+            ..Default::default()
+        };
+        let mut attempt = controlling(target, &[], &[], schedule);
+
+        assert!(attempt.probe_due(Duration::ZERO));
+        attempt.probe(Duration::ZERO);
+        assert!(!attempt.probe_due(Duration::from_millis(10)));
+
+        assert!(!attempt.on_ack(other, attempt.nonce, false));
+        assert!(!attempt.on_ack(target, attempt.nonce.wrapping_add(1), false));
+        assert!(attempt.on_ack(target, attempt.nonce, false));
+        assert!(!attempt.on_ack(target, attempt.nonce, false));
+
+        //The controlling side nominates the confirmed pair until the peer acknowledges the nomination
+        assert!(attempt.probe_due(Duration::from_secs(1)));
+        assert!(matches!(attempt.probe(Duration::from_secs(1)), PunchPacket::Probe { nominate: true, .. }));
+        assert_eq!(attempt.probe_targets(), [target]);
+        attempt.on_ack(target, attempt.nonce, true);
+        assert!(!attempt.probe_due(PUNCH_TIMEOUT));
+        assert_eq!(attempt.expired(PUNCH_TIMEOUT - Duration::from_millis(1)), None);
+        assert_eq!(attempt.expired(PUNCH_TIMEOUT), Some(TimeoutCause::Deadline));
+    }
+
+    #[test]
+    fn predicted_address_can_confirm() {
+        let target: SocketAddr = "198.51.100.4:40000".parse().unwrap();
+        let predicted: SocketAddr = "198.51.100.4:40006".parse().unwrap();
+        let mut attempt = controlling(target, &[], &[predicted, target], PunchSchedule::default());
+
+        assert_eq!(attempt.checklist.remote_addrs(), [target, predicted]);
+        assert!(attempt.on_ack(predicted, attempt.nonce, false));
+        assert_eq!(attempt.target, predicted);
+    }
+
+    #[test]
+    fn local_address_wins_when_it_answers_first() {
+        let target: SocketAddr = "198.51.100.4:40000".parse().unwrap();
+        let local: SocketAddr = "192.168.1.20:40000".parse().unwrap();
+        let predicted: SocketAddr = "198.51.100.4:40006".parse().unwrap();
+        let mut attempt = controlling(target, &[local], &[predicted], PunchSchedule::default());
+
+        assert_eq!(attempt.checklist.remote_addrs(), [local, target, predicted]);
+        assert!(attempt.on_ack(local, attempt.nonce, false));
+        assert!(!attempt.on_ack(target, attempt.nonce, false));
+        assert_eq!(attempt.target, local);
+    }
+
+    #[test]
+    fn controlled_side_waits_for_nomination() {
+        let public: SocketAddr = "198.51.100.4:40000".parse().unwrap();
+        let local: SocketAddr = "192.168.1.20:40000".parse().unwrap();
+        let remote = [
+            Candidate::new(CandidateKind::Host, local, u16::MAX),
+            Candidate::new(CandidateKind::ServerReflexive, public, u16::MAX),
+        ];
+        let schedule = PunchSchedule::default();
+        let mut attempt =
+            PunchAttempt::from_candidates(public, Role::Controlled, &[], &remote, Duration::ZERO, schedule).unwrap();
+
+        assert_eq!(attempt.target, local);
+        assert!(!attempt.on_ack(local, attempt.nonce, false));
+        assert!(!attempt.confirmed);
+        assert!(attempt.on_nominated(public));
+        assert!(!attempt.on_nominated(local));
+        assert_eq!(attempt.target, public);
+        assert!(!attempt.probe_due(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn schedule_bursts_then_backs_off() {
+        let schedule = PunchSchedule {
+            burst: 3,
+            interval: Duration::from_millis(100),
+            backoff: 2.0,
+            max_interval: Duration::from_millis(300),
+            ..Default::default()
+        };
+
+        let waits: Vec<u128> = (0..7).map(|sent| schedule.wait_after(sent).as_millis()).collect();
+        assert_eq!(waits, [0, 0, 0, 100, 200, 300, 300]);
+    }
+
+    #[test]
+    fn attempt_gives_up_after_max_probes() {
+        let target: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let schedule = PunchSchedule {
+            burst: 2,
+            interval: Duration::from_millis(100),
+            backoff: 1.0,
+            max_probes: 3,
+            ..Default::default()
+        };
+        let mut attempt = controlling(target, &[], &[], schedule);
+
+        let mut now = Duration::ZERO;
+        while attempt.probe_due(now) {
+            attempt.probe(now);
+        }
+        assert_eq!(attempt.probes_sent, 2);
+
+        now += Duration::from_millis(100);
+        assert!(attempt.probe_due(now));
+        attempt.probe(now);
+        assert!(!attempt.probe_due(now + Duration::from_secs(1)));
+
+        assert_eq!(attempt.expired(now + Duration::from_millis(50)), None);
+        assert_eq!(attempt.expired(now + Duration::from_millis(100)), Some(TimeoutCause::ProbesExhausted));
+    }
+
+    #[test]
+    fn silent_peer_is_reported_once() {
+        let schedule = KeepaliveSchedule {
+            peer_interval: Duration::from_secs(5),
+            peer_timeout: Duration::from_secs(12),
+            ..Default::default()
+        };
+        let mut link = PeerLink::new("127.0.0.1:5000".parse().unwrap(), Duration::ZERO);
+
+        assert!(!link.keepalive_due(Duration::from_secs(4), &schedule));
+        assert!(link.keepalive_due(Duration::from_secs(5), &schedule));
+        link.on_sent(Duration::from_secs(5));
+        link.on_received(Duration::from_secs(5));
+        assert!(!link.check_unresponsive(Duration::from_secs(16), &schedule));
+
+        link.on_sent(Duration::from_secs(10));
+        link.on_sent(Duration::from_secs(15));
+        assert!(link.check_unresponsive(Duration::from_secs(17), &schedule));
+        assert!(!link.check_unresponsive(Duration::from_secs(18), &schedule));
+        assert_eq!((link.keepalives_sent, link.keepalives_received), (3, 1));
+
+        link.on_received(Duration::from_secs(20));
+        assert!(!link.unresponsive);
+    }
+
+    #[test]
+    fn nothing_to_punch_towards_is_no_attempt() {
+        let peer: SocketAddr = "198.51.100.4:40000".parse().unwrap();
+        let schedule = PunchSchedule::default();
+
+        assert!(PunchAttempt::from_candidates(peer, Role::Controlling, &[], &[], Duration::ZERO, schedule).is_none());
+    }
+}
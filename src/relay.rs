@@ -0,0 +1,265 @@
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use rand::RngCore;
+
+use crate::settings::RelaySettings;
+
+/// Why a relayed datagram was not forwarded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayDrop {
+    /// The token doesn't belong to any open session
+    UnknownToken,
+    /// The session used up its bandwidth, see RelaySettings::bytes_per_second
+    OverBandwidth,
+    /// The other side hasn't sent anything to the relay yet, so there is nowhere to send it
+    PeerNotRegistered,
+}
+
+/// Two clients that could not punch through, whose datagrams the server forwards to each other.
+/// Each side is identified on the relay socket by its own token
+#[derive(Debug, Clone)]
+pub struct RelaySession {
+    pub clients: [u64; 2],
+    pub tokens: [u64; 2],
+    /// Where each side's datagrams last came from, unknown until it sends its first one
+    pub addrs: [Option<SocketAddr>; 2],
+    pub opened_at: Duration,
+    pub bytes_forwarded: u64,
+    budget: f64,
+    budget_updated_at: Duration,
+}
+
+impl RelaySession {
+    fn side_of_client(&self, client_id: u64) -> Option<usize> {
+        self.clients.iter().position(|id| *id == client_id)
+    }
+
+    fn side_of_token(&self, token: u64) -> Option<usize> {
+        self.tokens.iter().position(|t| *t == token)
+    }
+
+    /// The token the given client uses in this session
+    pub fn token_of(&self, client_id: u64) -> Option<u64> {
+        self.side_of_client(client_id).map(|side| self.tokens[side])
+    }
+
+    pub fn expired(&self, now: Duration, settings: &RelaySettings) -> bool {
+        now.saturating_sub(self.opened_at) >= settings.max_session()
+    }
+
+    /// Spends bytes from the session's bandwidth budget if there are enough left
+    fn spend(&mut self, bytes: usize, settings: &RelaySettings, now: Duration) -> bool {
+        let elapsed = now.saturating_sub(self.budget_updated_at).as_secs_f64();
+        self.budget = (self.budget + elapsed * settings.bytes_per_second as f64).min(settings.burst_bytes as f64);
+        self.budget_updated_at = now;
+
+        if self.budget >= bytes as f64 {
+            self.budget -= bytes as f64;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Every open relay session on the server, keyed by the first client's token
+#[derive(Debug, Default)]
+pub struct RelayRegistry {
+    sessions: HashMap<u64, RelaySession>,
+    /// Each token to the session it belongs to
+    tokens: HashMap<u64, u64>,
+}
+
+impl RelayRegistry {
+    /// Opens a session between two clients with a fresh token for each. Starts with a full burst budget
+    pub fn open(
+        &mut self,
+        a: u64,
+        b: u64,
+        settings: &RelaySettings,
+        now: Duration,
+        rng: &mut dyn RngCore,
+    ) -> &RelaySession {
+        let mut tokens = [0; 2];
+        for side in 0..2 {
+            tokens[side] = loop {
+                let token = rng.next_u64();
+                if !self.tokens.contains_key(&token) && !tokens[..side].contains(&token) {
+                    break token;
+                }
+            };
+        }
+
+        self.tokens.insert(tokens[0], tokens[0]);
+        self.tokens.insert(tokens[1], tokens[0]);
+        self.sessions.entry(tokens[0]).or_insert(RelaySession {
+            clients: [a, b],
+            tokens,
+            addrs: [None, None],
+            opened_at: now,
+            bytes_forwarded: 0,
+            budget: settings.burst_bytes as f64,
+            budget_updated_at: now,
+        })
+    }
+
+    /// The session between two clients, whichever of them asked for it
+    pub fn between(&self, a: u64, b: u64) -> Option<&RelaySession> {
+        self.sessions.values().find(|session| {
+            session.side_of_client(a).is_some() && session.side_of_client(b).is_some()
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Records where a datagram carrying token came from, and returns where to send its payload along with the
+    /// receiving side's token. Ok(None) for empty payloads, which only register the sender's address
+    pub fn forward(
+        &mut self,
+        token: u64,
+        from: SocketAddr,
+        bytes: usize,
+        settings: &RelaySettings,
+        now: Duration,
+    ) -> Result<Option<(SocketAddr, u64)>, RelayDrop> {
+        let session = self
+            .tokens
+            .get(&token)
+            .and_then(|id| self.sessions.get_mut(id))
+            .ok_or(RelayDrop::UnknownToken)?;
+        let side = session.side_of_token(token).ok_or(RelayDrop::UnknownToken)?;
+        session.addrs[side] = Some(from);
+        if bytes == 0 {
+            return Ok(None);
+        }
+
+        let other = 1 - side;
+        let to = session.addrs[other].ok_or(RelayDrop::PeerNotRegistered)?;
+        if !session.spend(bytes, settings, now) {
+            return Err(RelayDrop::OverBandwidth);
+        }
+        session.bytes_forwarded += bytes as u64;
+        Ok(Some((to, session.tokens[other])))
+    }
+
+    /// Closes and returns every session that outlived RelaySettings::max_session_secs
+    pub fn expire(&mut self, now: Duration, settings: &RelaySettings) -> Vec<RelaySession> {
+        let expired: Vec<u64> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.expired(now, settings))
+            .map(|(id, _)| *id)
+            .collect();
+        expired.into_iter().filter_map(|id| self.remove(id)).collect()
+    }
+
+    /// Closes and returns every session a disconnected client was part of
+    pub fn remove_client(&mut self, client_id: u64) -> Vec<RelaySession> {
+        let sessions: Vec<u64> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.side_of_client(client_id).is_some())
+            .map(|(id, _)| *id)
+            .collect();
+        sessions.into_iter().filter_map(|id| self.remove(id)).collect()
+    }
+
+    /// Hands a client's sessions to the client it resumed its session as, see ClientToServer::Resume. Its side keeps
+    /// its token and is sent to wherever that token is next used from
+    pub fn transfer(&mut self, from: u64, to: u64) {
+        for session in self.sessions.values_mut() {
+            if let Some(side) = session.side_of_client(from) {
+                session.clients[side] = to;
+            }
+        }
+    }
+
+    fn remove(&mut self, id: u64) -> Option<RelaySession> {
+        let session = self.sessions.remove(&id)?;
+        for token in session.tokens.iter() {
+            self.tokens.remove(token);
+        }
+        Some(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([203, 0, 113, 7], port))
+    }
+
+    #[test]
+    fn datagrams_are_forwarded_within_budget() {
+        let settings = RelaySettings {
+            bytes_per_second: 1000,
+            burst_bytes: 1500,
+            ..Default::default()
+        };
+        let mut relays = RelayRegistry::default();
+        let tokens = relays.open(1, 2, &settings, Duration::ZERO, &mut StdRng::seed_from_u64(7)).tokens;
+        assert_eq!(relays.between(2, 1).map(|session| session.tokens), Some(tokens));
+
+        let now = Duration::ZERO;
+        assert_eq!(relays.forward(tokens[0], addr(1), 0, &settings, now), Ok(None));
+        assert_eq!(relays.forward(tokens[0], addr(1), 100, &settings, now), Err(RelayDrop::PeerNotRegistered));
+        assert_eq!(relays.forward(tokens[1], addr(2), 1000, &settings, now), Ok(Some((addr(1), tokens[0]))));
+        assert_eq!(relays.forward(tokens[0], addr(1), 1000, &settings, now), Err(RelayDrop::OverBandwidth));
+
+        let later = Duration::from_millis(500);
+        assert_eq!(relays.forward(tokens[0], addr(3), 1000, &settings, later), Ok(Some((addr(2), tokens[1]))));
+        assert_eq!(relays.forward(7, addr(3), 10, &settings, later), Err(RelayDrop::UnknownToken));
+    }
+
+    #[test]
+    fn sessions_close_on_expiry_and_disconnect() {
+        let settings = RelaySettings {
+            max_session_secs: 60,
+            ..Default::default()
+        };
+        let mut relays = RelayRegistry::default();
+        let mut rng = StdRng::seed_from_u64(7);
+        let first = relays.open(1, 2, &settings, Duration::ZERO, &mut rng).tokens;
+        relays.open(1, 3, &settings, Duration::from_secs(30), &mut rng);
+
+        let expired = relays.expire(Duration::from_secs(60), &settings);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].clients, [1, 2]);
+        assert_eq!(
+            relays.forward(first[0], addr(1), 0, &settings, Duration::from_secs(60)),
+            Err(RelayDrop::UnknownToken)
+        );
+
+        assert_eq!(relays.remove_client(3).len(), 1);
+        assert!(relays.is_empty());
+    }
+
+    #[test]
+    fn resumed_clients_keep_their_sessions() {
+        let settings = RelaySettings::default();
+        let mut relays = RelayRegistry::default();
+        let tokens = relays.open(1, 2, &settings, Duration::ZERO, &mut StdRng::seed_from_u64(7)).tokens;
+        relays.forward(tokens[0], addr(1), 0, &settings, Duration::ZERO).unwrap();
+        relays.forward(tokens[1], addr(2), 0, &settings, Duration::ZERO).unwrap();
+
+        relays.transfer(1, 3);
+        assert!(relays.between(1, 2).is_none());
+        assert_eq!(relays.between(3, 2).unwrap().token_of(3), Some(tokens[0]));
+        //The old connection disconnecting later leaves the session alone
+        assert!(relays.remove_client(1).is_empty());
+
+        //The moved side is found at its new address as soon as it uses its token again
+        relays.forward(tokens[0], addr(4), 0, &settings, Duration::ZERO).unwrap();
+        assert_eq!(relays.forward(tokens[1], addr(2), 10, &settings, Duration::ZERO), Ok(Some((addr(4), tokens[0]))));
+    }
+}
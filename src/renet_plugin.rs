@@ -5,25 +5,100 @@ use bevy::{
     prelude::*,
 };
 
+use std::{collections::HashMap, net::SocketAddr};
+
 use renet::{RenetError, RenetServer, ServerEvent};
 
 use crate::client::PunchthroughClientRes;
 
 pub struct PTRenetServerPlugin;
 
+/// One or more RenetServers, one per bound address, that look like a single server to the rest of the plugin.
+/// Clients are routed to the endpoint they connected through
+pub struct PTRenetServer {
+    pub endpoints: Vec<RenetServer>,
+    client_endpoints: HashMap<u64, usize>,
+}
+
+impl PTRenetServer {
+    pub fn new(endpoints: Vec<RenetServer>) -> Self {
+        Self {
+            endpoints,
+            client_endpoints: HashMap::new(),
+        }
+    }
+
+    /// Next event from any endpoint, keeping track of which endpoint each client is on
+    pub fn get_event(&mut self) -> Option<ServerEvent> {
+        for (index, endpoint) in self.endpoints.iter_mut().enumerate() {
+            if let Some(event) = endpoint.get_event() {
+                match &event {
+                    ServerEvent::ClientConnected(id, _) => {
+                        self.client_endpoints.insert(*id, index);
+                    }
+                    ServerEvent::ClientDisconnected(id) => {
+                        self.client_endpoints.remove(id);
+                    }
+                }
+                return Some(event);
+            }
+        }
+
+        None
+    }
+
+    pub fn clients_id(&self) -> Vec<u64> {
+        self.client_endpoints.keys().copied().collect()
+    }
+
+    pub fn endpoint_of(&mut self, client_id: u64) -> Option<&mut RenetServer> {
+        let index = *self.client_endpoints.get(&client_id)?;
+        self.endpoints.get_mut(index)
+    }
+
+    /// Public address of the client as observed by the endpoint it is connected to
+    pub fn client_addr(&self, client_id: u64) -> Option<SocketAddr> {
+        let index = *self.client_endpoints.get(&client_id)?;
+        self.endpoints.get(index)?.netcode_server.client_addr(client_id)
+    }
+
+    pub fn send_message(&mut self, client_id: u64, channel_id: u8, message: Vec<u8>) {
+        match self.endpoint_of(client_id) {
+            Some(endpoint) => endpoint.send_message(client_id, channel_id, message),
+            None => warn!("Dropped message to unknown client {client_id}"),
+        }
+    }
+
+    pub fn receive_message(&mut self, client_id: u64, channel_id: u8) -> Option<Vec<u8>> {
+        self.endpoint_of(client_id)?.receive_message(client_id, channel_id)
+    }
+
+    pub fn disconnect(&mut self, client_id: u64) {
+        if let Some(endpoint) = self.endpoint_of(client_id) {
+            endpoint.disconnect(client_id);
+        }
+    }
+}
+
 pub struct PTRenetClientPlugin;
 
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PTRenetSystem {
+    /// Updates the rendezvous connection with the datagrams client::receive_punch_packets drained before it
+    ClientUpdate,
+}
+
 impl Plugin for PTRenetServerPlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<ServerEvent>()
             .add_event::<RenetError>()
             .add_system_to_stage(
                 CoreStage::PreUpdate,
-                Self::update_system.with_run_criteria(has_resource::<RenetServer>),
+                Self::update_system.with_run_criteria(has_resource::<PTRenetServer>),
             )
             .add_system_to_stage(
                 CoreStage::PostUpdate,
-                Self::send_packets_system.with_run_criteria(has_resource::<RenetServer>),
+                Self::send_packets_system.with_run_criteria(has_resource::<PTRenetServer>),
             );
     }
 }
@@ -33,7 +108,9 @@ impl Plugin for PTRenetClientPlugin {
         app.add_event::<RenetError>()
             .add_system_to_stage(
                 CoreStage::PreUpdate,
-                Self::update_system.with_run_criteria(has_resource::<PunchthroughClientRes>),
+                Self::update_system
+                    .with_run_criteria(has_resource::<PunchthroughClientRes>)
+                    .label(PTRenetSystem::ClientUpdate),
             )
             .add_system_to_stage(
                 CoreStage::PostUpdate,
@@ -44,13 +121,15 @@ impl Plugin for PTRenetClientPlugin {
 
 impl PTRenetServerPlugin {
     pub fn update_system(
-        mut server: ResMut<RenetServer>,
+        mut server: ResMut<PTRenetServer>,
         mut renet_error: EventWriter<RenetError>,
         time: Res<Time>,
         mut server_events: EventWriter<ServerEvent>,
     ) {
-        if let Err(e) = server.update(time.delta()) {
-            renet_error.send(RenetError::IO(e));
+        for endpoint in server.endpoints.iter_mut() {
+            if let Err(e) = endpoint.update(time.delta()) {
+                renet_error.send(RenetError::IO(e));
+            }
         }
 
         while let Some(event) = server.get_event() {
@@ -58,9 +137,11 @@ impl PTRenetServerPlugin {
         }
     }
 
-    pub fn send_packets_system(mut server: ResMut<RenetServer>, mut renet_error: EventWriter<RenetError>) {
-        if let Err(e) = server.send_packets() {
-            renet_error.send(RenetError::IO(e));
+    pub fn send_packets_system(mut server: ResMut<PTRenetServer>, mut renet_error: EventWriter<RenetError>) {
+        for endpoint in server.endpoints.iter_mut() {
+            if let Err(e) = endpoint.send_packets() {
+                renet_error.send(RenetError::IO(e));
+            }
         }
     }
 }
@@ -79,7 +160,7 @@ impl PTRenetClientPlugin {
     }
 }
 
-fn has_resource<T: Resource>(resource: Option<Res<T>>) -> ShouldRun {
+pub fn has_resource<T: Resource>(resource: Option<Res<T>>) -> ShouldRun {
     match resource.is_some() {
         true => ShouldRun::Yes,
         false => ShouldRun::No,
@@ -95,11 +176,13 @@ pub fn run_if_client_connected(client_res: Option<Res<PunchthroughClientRes>>) -
 
 #[cfg(test)]
 mod tests {
-    use crate::bevy_renet::renet::{ClientAuthentication, RenetConnectionConfig, ServerAuthentication, ServerConfig};
+    use crate::bevy_renet::renet::{
+        ClientAuthentication, RenetClient, RenetConnectionConfig, ServerAuthentication, ServerConfig,
+    };
     use std::{
         error::Error,
         net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
-        time::SystemTime,
+        time::{Duration, SystemTime},
     };
 
     use super::*;
@@ -107,33 +190,32 @@ mod tests {
     #[test]
     fn sending_and_receiving_messages() {
         let server = create_server().unwrap();
-        let client = create_client().unwrap();
+        let mut client = create_client().unwrap();
 
         let mut app = App::new();
         app.add_plugins(MinimalPlugins)
             .add_plugin(PTRenetServerPlugin)
-            .add_plugin(PTRenetClientPlugin)
-            .insert_resource(server)
-            .insert_resource(client);
-
-        app.update();
-        app.update();
-        app.update();
+            .insert_resource(PTRenetServer::new(vec![server]));
 
-        let client = app.world.resource::<RenetClient>();
+        for _ in 0..10 {
+            if client.is_connected() {
+                break;
+            }
+            update(&mut app, &mut client);
+        }
         assert!(client.is_connected(), "The client should be connected to the server",);
 
         let client_id = client.client_id();
+        assert_eq!(app.world.resource::<PTRenetServer>().clients_id(), [client_id]);
         for index in 0..10 {
             // Send message from server to client
             let server_message = format!("Hello from server {}", index).as_bytes().to_vec();
-            let mut server = app.world.resource_mut::<RenetServer>();
+            let mut server = app.world.resource_mut::<PTRenetServer>();
             server.send_message(client_id, 0, server_message.clone());
 
-            app.update();
-            app.update();
+            update(&mut app, &mut client);
+            update(&mut app, &mut client);
 
-            let mut client = app.world.resource_mut::<RenetClient>();
             let message = client.receive_message(0).expect("Unable to receive message from server");
             assert_eq!(message, server_message);
 
@@ -141,15 +223,22 @@ mod tests {
             let client_message = format!("Hello from client {}", index).as_bytes().to_vec();
             client.send_message(0, client_message.clone());
 
-            app.update();
-            app.update();
+            update(&mut app, &mut client);
+            update(&mut app, &mut client);
 
-            let mut server = app.world.resource_mut::<RenetServer>();
+            let mut server = app.world.resource_mut::<PTRenetServer>();
             let message = server.receive_message(client_id, 0).expect("Unable to receive message from client");
             assert_eq!(message, client_message);
         }
     }
 
+    /// Runs the server for a frame. PTRenetClientPlugin only drives a PunchthroughClientRes, so this drives the client
+    fn update(app: &mut App, client: &mut RenetClient) {
+        app.update();
+        client.update(Duration::from_millis(16)).unwrap();
+        client.send_packets().unwrap();
+    }
+
     const SERVER_PORT: u16 = 4444;
     const SERVER_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
     const PROTOCOL_ID: u64 = 7;
@@ -1,24 +1,174 @@
 use std::{
     collections::HashMap,
+    fmt,
+    io::{self, ErrorKind},
     net::{SocketAddr, UdpSocket},
-    time::SystemTime,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
 
 use bevy::prelude::*;
 use bevy_renet::{
     renet::{RenetConnectionConfig, RenetServer, ServerAuthentication, ServerConfig, ServerEvent},
 };
-use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use rand::thread_rng;
+use serde::{Deserialize, Deserializer};
 
-use crate::{ClientChannel, ClientHostMessage, ServerChannel, PROTOCOL_ID, renet_plugin::PTRenetServerPlugin};
+use crate::{
+    auth::ServerAuth,
+    candidate::{self, Role},
+    lobby::{Lobby, LobbyCodeGenerator, LobbyFilter, LobbyInfo, LobbyPage, LobbyRegistry},
+    nat::{canonical, ClientMapping, ConnectStrategy, EchoPath, NatType},
+    punch::{PunchPacket, MAX_DATAGRAM_SIZE},
+    relay::{RelayDrop, RelayRegistry, RelaySession},
+    renet_plugin::{PTRenetServer, PTRenetServerPlugin},
+    protocol::{AddressCheck, Capabilities, ClientToServer, ProtocolError, RequestId, ServerToClient},
+    settings::{ConfigError, ServerSettings, SettingsOverrides, SettingsWatcher, TokenBucket},
+    ClientChannel, ClientError, ClientMessage, HelloResponse, ServerChannel, ServerMessage, ServerMessaging,
+};
+
+/// Local addresses kept per client, anything past this is dropped
+pub const MAX_LOCAL_ADDRS: usize = 4;
 
 pub struct PunchThroughServerRes {
-    pub hosts: HashMap<String, (u64, SocketAddr)>,
-    pub host_client_idx: HashMap<u64, (String, SocketAddr)>,
+    pub lobbies: LobbyRegistry,
+    pub lobby_codes: Arc<dyn LobbyCodeGenerator>,
+    /// Clients that completed the Hello handshake, with the capabilities both sides support
+    pub client_capabilities: HashMap<u64, Capabilities>,
+    /// Clients that failed the Hello handshake and when, they are disconnected after the rejected client grace period
+    pub rejected_clients: HashMap<u64, Duration>,
+    pub rate_limiters: HashMap<u64, TokenBucket>,
+    /// How each client's NAT maps its socket, for clients that support port prediction
+    pub mappings: HashMap<u64, ClientMapping>,
+    /// Mapping probe tokens to the client they were handed to
+    pub mapping_tokens: HashMap<u64, u64>,
+    /// Peers that could not punch through and talk through the relay socket instead
+    pub relays: RelayRegistry,
+    /// Each welcomed client's public address as seen at its Hello. Netcode keys connections by address, so a client
+    /// that moved shows up as a new connection and carries its session over with ClientToServer::Resume
+    pub observed_addrs: HashMap<u64, SocketAddr>,
+    /// The token each welcomed client can resume its session from another socket with, see ClientToServer::Resume
+    pub resume_tokens: HashMap<u64, u64>,
+    /// Address check tokens to the client they were handed to, see AddressCheck
+    pub address_tokens: HashMap<u64, u64>,
+    /// Where each client's address keepalives first reached the observer from. One from anywhere else means its
+    /// public address moved
+    pub keepalive_addrs: HashMap<u64, SocketAddr>,
+}
+
+/// The socket relayed datagrams go through, see RelaySettings
+pub struct RelaySocket {
+    pub socket: UdpSocket,
+}
+
+/// Plain UDP sockets that echo back the address mapping probes arrive from, see NatSettings
+pub struct MappingObservers {
+    pub sockets: Vec<UdpSocket>,
+    /// Sends the ChangedPort echo, from a port no client ever sends to
+    pub changed_port: Option<UdpSocket>,
+    /// Sends the ChangedIp echo, see NatSettings::alternate_ip
+    pub changed_ip: Option<UdpSocket>,
+}
+
+/// An address the rendezvous server listens on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerBind {
+    /// Local address the socket is bound to, e.g. 0.0.0.0:5000 or [::]:5000
+    pub addr: SocketAddr,
+    /// Address clients dial to reach this socket, when it differs from addr. Set this behind a load balancer or
+    /// container port mapping, and whenever addr is unspecified, since netcode rejects clients that dialed any other address
+    pub public_addr: Option<SocketAddr>,
+}
+
+impl ServerBind {
+    pub fn public_addr(&self) -> SocketAddr {
+        self.public_addr.unwrap_or(self.addr)
+    }
+}
+
+impl From<SocketAddr> for ServerBind {
+    fn from(addr: SocketAddr) -> Self {
+        Self { addr, public_addr: None }
+    }
 }
 
+impl FromStr for ServerBind {
+    type Err = std::net::AddrParseError;
+
+    /// Either ADDR or ADDR=PUBLIC_ADDR, e.g. "0.0.0.0:5000=203.0.113.7:5000"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((addr, public_addr)) => Ok(Self {
+                addr: addr.trim().parse()?,
+                public_addr: Some(public_addr.trim().parse()?),
+            }),
+            None => Ok(Self::from(s.trim().parse::<SocketAddr>()?)),
+        }
+    }
+}
+
+/// Read from settings files in the same ADDR or ADDR=PUBLIC_ADDR form
+impl<'de> Deserialize<'de> for ServerBind {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for ServerBind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.public_addr {
+            Some(public_addr) => write!(f, "{}={public_addr}", self.addr),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+
 pub struct PunchThroughServerPlugin{
-    pub port: u16
+    pub settings: ServerSettings,
+    /// File the settings were loaded from. When set it is watched, and safe changes are applied without a restart
+    pub settings_file: Option<PathBuf>,
+    /// Already applied to settings. Applied again over the settings file whenever it is reloaded
+    pub settings_overrides: SettingsOverrides,
+    /// Replaces settings.lobbies.codes with your own generator
+    pub lobby_codes: Option<Arc<dyn LobbyCodeGenerator>>,
+    /// Use ServerAuth::Secure (or ServerAuth::from_env) for anything reachable from the internet
+    pub authentication: ServerAuth,
+}
+
+impl Default for PunchThroughServerPlugin {
+    fn default() -> Self {
+        Self {
+            settings: ServerSettings::default(),
+            settings_file: None,
+            settings_overrides: SettingsOverrides::default(),
+            lobby_codes: None,
+            authentication: ServerAuth::Unsecure,
+        }
+    }
+}
+
+impl PunchThroughServerPlugin {
+    pub fn lobby_codes(&self) -> Arc<dyn LobbyCodeGenerator> {
+        match &self.lobby_codes {
+            Some(lobby_codes) => lobby_codes.clone(),
+            None => self.settings.lobbies.codes.generator(),
+        }
+    }
+
+    /// Checks the settings without binding any sockets
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.settings.validate()?;
+
+        if self.lobby_codes().generate(&mut thread_rng()).is_empty() {
+            return Err(ConfigError::EmptyLobbyCode);
+        }
+
+        Ok(())
+    }
 }
 
 impl Plugin for PunchThroughServerPlugin {
@@ -26,10 +176,77 @@ impl Plugin for PunchThroughServerPlugin {
         info!("Building Plugin");
         app.add_plugin(PTRenetServerPlugin);
         app.insert_resource(PunchThroughServerRes {
-            hosts: HashMap::new(),
-            host_client_idx: HashMap::new(),
+            lobbies: LobbyRegistry::default(),
+            lobby_codes: self.lobby_codes(),
+            client_capabilities: HashMap::new(),
+            rejected_clients: HashMap::new(),
+            rate_limiters: HashMap::new(),
+            mappings: HashMap::new(),
+            mapping_tokens: HashMap::new(),
+            relays: RelayRegistry::default(),
+            observed_addrs: HashMap::new(),
+            resume_tokens: HashMap::new(),
+            address_tokens: HashMap::new(),
+            keepalive_addrs: HashMap::new(),
         });
-        app.insert_resource(get_server(self.port));
+        //Like the game binds, a feature whose socket can't be bound is turned off rather than taking the server down
+        let mut settings = self.settings.clone();
+        let observers = match bind_observers(&settings.nat.observers) {
+            Ok(observers) => observers,
+            Err(e) => {
+                warn!("Not observing NAT mappings because an observer could not be bound: {e}");
+                settings.nat.observers.clear();
+                MappingObservers { sockets: Vec::new(), changed_port: None, changed_ip: None }
+            }
+        };
+        let changed_ip = match settings.nat.alternate_ip.filter(|_| !observers.sockets.is_empty()) {
+            Some(addr) => match bind_socket(addr) {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    warn!("Not echoing from the alternate IP because {addr} could not be bound: {e}");
+                    None
+                }
+            },
+            None => None,
+        };
+        app.insert_resource(MappingObservers { changed_ip, ..observers });
+        app.add_system(observe_mappings.before(process_server_events));
+        if let Some(bind) = settings.relay.bind {
+            match bind_socket(bind.addr) {
+                Ok(socket) => {
+                    info!("Relaying datagrams on {} reachable at {}", bind.addr, bind.public_addr());
+                    app.insert_resource(RelaySocket { socket });
+                    app.add_system(relay_datagrams.before(process_server_events));
+                }
+                Err(e) => {
+                    warn!("Not relaying because {} could not be bound: {e}", bind.addr);
+                    settings.relay.bind = None;
+                }
+            }
+        }
+        //A host without IPv6 can't bind the default [::1], the server still runs on what it could bind
+        let endpoints: Vec<RenetServer> = self
+            .settings
+            .binds
+            .iter()
+            .filter_map(|bind| match get_server(bind, self) {
+                Ok(server) => Some(server),
+                Err(e) => {
+                    warn!("Not listening on {} because it could not be bound: {e}", bind.addr);
+                    None
+                }
+            })
+            .collect();
+        if endpoints.is_empty() {
+            panic!("Could not bind any of the configured addresses");
+        }
+        app.insert_resource(PTRenetServer::new(endpoints));
+        app.insert_resource(settings);
+        if let Some(path) = &self.settings_file {
+            let watcher = SettingsWatcher::new(path.clone(), self.settings_overrides.clone(), self.settings.clone());
+            app.insert_resource(watcher);
+            app.add_system(reload_settings.before(process_server_events));
+        }
         app.add_system(process_server_events);
         app.add_startup_system(server_plugin_init);
     }
@@ -42,151 +259,795 @@ fn server_plugin_init(){
 fn process_server_events(
     mut server_events: EventReader<ServerEvent>,
     mut server_res: ResMut<PunchThroughServerRes>,
-    mut server: ResMut<RenetServer>,
+    mut server: ResMut<PTRenetServer>,
+    settings: Res<ServerSettings>,
+    time: Res<Time>,
 ) {
     let pt_res = server_res.as_mut();
+    let now = time.time_since_startup();
 
     for server_event in server_events.iter() {
         match server_event {
             ServerEvent::ClientConnected(id, _user_data) => {
-                let client_addr = server.netcode_server.client_addr(*id).unwrap();
-                println!(
-                    "Client connected: {} on {}:{}",
-                    id,
-                    client_addr.ip(),
-                    client_addr.port()
-                );
+                //A client that connected and left within one update is already gone from netcode
+                match server.client_addr(*id) {
+                    Some(client_addr) => info!("Client connected: {id} on {client_addr}"),
+                    None => debug!("Client {id} disconnected before its connection was handled"),
+                }
             }
 
             ServerEvent::ClientDisconnected(id) => {
-                if pt_res.host_client_idx.contains_key(id) {
-                    let host_id = pt_res.host_client_idx.get(id).unwrap();
-                    pt_res.hosts.remove(&host_id.0);
-                    pt_res.host_client_idx.remove(id);
+                pt_res.client_capabilities.remove(id);
+                pt_res.rejected_clients.remove(id);
+                pt_res.rate_limiters.remove(id);
+                pt_res.observed_addrs.remove(id);
+                pt_res.resume_tokens.remove(id);
+                pt_res.address_tokens.retain(|_, client| client != id);
+                pt_res.keepalive_addrs.remove(id);
+                if let Some(mapping) = pt_res.mappings.remove(id) {
+                    pt_res.mapping_tokens.remove(&mapping.token);
+                }
+                for session in pt_res.relays.remove_client(*id) {
+                    notify_relay_closed(&mut server, &session);
+                }
+                if let Some(lobby) = pt_res.lobbies.remove_client(*id) {
+                    info!("Host {id} disconnected, closing lobby {}", lobby.id);
+                    notify_lobby_closed(&mut server, &lobby);
                 }
 
-                println!("Client disconnected: {}", id);
+                info!("Client disconnected: {id}");
             }
         }
     }
 
     //Parse messages from the clients
-    let punchthrough_res = server_res.as_mut();
-
     for client_id in server.clients_id().into_iter() {
-        while let Some(message) = server
-            .receive_message(client_id, ClientChannel::Command.id())
-        {
-            let cmd: ClientHostMessage = match bincode::deserialize(&message) {
-                Ok(cmd) => cmd,
+        if !pt_res.client_capabilities.contains_key(&client_id) {
+            receive_hello(&mut server, pt_res, &settings, client_id, now);
+        }
+
+        if !pt_res.client_capabilities.contains_key(&client_id) {
+            continue;
+        }
+
+        while let Some(message) = server.receive_from_client(client_id) {
+            let ClientMessage { request_id, body } = match message {
+                Ok(message) => message,
                 Err(e) => {
-                    println!("Error deserializing client host command {e:#?}");
+                    warn!("Could not deserialize message from client {client_id}: {e:#?}");
+                    server.send_to_client(
+                        client_id,
+                        ServerMessage::notification(ServerToClient::Error {
+                            err: ClientError::Protocol(ProtocolError::Malformed),
+                        }),
+                    );
                     continue;
                 }
             };
 
-            match cmd {
-                ClientHostMessage::HostNewLobby => {
-                    let addr = server
-                        .netcode_server
-                        .client_addr(client_id)
-                        .unwrap();
-                    //Construct Host Lobby of LobbyId, SocketAddr
-                    let random_str: Vec<u8> =
-                        thread_rng().sample_iter(&Alphanumeric).take(5).collect();
-
-                    //TODO: Put code in to ensure this is unique
-                    let id = match String::from_utf8(random_str) {
-                        Ok(id) => id.to_ascii_uppercase(),
-                        Err(e) => {
-                            println!("Error generating id: {e:#?}");
-                            continue;
-                        }
-                    };
-
-                    punchthrough_res.hosts.insert(id.clone(), (client_id, addr));
-                    let message = bincode::serialize(&ClientHostMessage::NewLobbyResponse {
-                        lobby_id: id.clone(),
-                    })
-                    .expect("Could not encode id to bytes");
-                    server.send_message(
-                        client_id,
-                        ClientChannel::Command.id(),
-                        message,
-                    )
-                }
-
-                ClientHostMessage::RequestSwap { lobby_id } => {
-                    if punchthrough_res.hosts.contains_key(&lobby_id.to_ascii_uppercase()) {
-                        let message =
-                            bincode::serialize(&ClientHostMessage::JoinLobbyResponse { err: None })
-                                .expect("Could not deserialize JoinLobbyResponse to bytes.");
-                        server.send_message(
-                            client_id,
-                            ClientChannel::Command.id(),
-                            message,
-                        );
-
-                        //Send handshake command to client
-                        if let Some(socket) = server
-                            .netcode_server
-                            .client_addr(client_id)
-                        {
-                            let client_swap_message =
-                                bincode::serialize(&ClientHostMessage::AttemptHandshakeCommand {
-                                    socket,
-                                })
-                                .expect("Error serializing Client_Swap_Message to bytes");
-                            server.send_message(
-                                client_id,
-                                ClientChannel::Command.id(),
-                                client_swap_message,
-                            );
-
-                            //Send handshake command to client client
-                            let host_client = punchthrough_res.hosts.get(&lobby_id.to_uppercase()).unwrap();
-                            let server_swap_message =
-                                bincode::serialize(&ClientHostMessage::AttemptHandshakeCommand {
-                                    socket: host_client.1,
-                                })
-                                .expect("Could not serialize swap message to bytes");
-                            server.send_message(
-                                host_client.0,
-                                ClientChannel::Command.id(),
-                                server_swap_message,
-                            );
-                        } else {
-                            let join_response =
-                                bincode::serialize(&ClientHostMessage::JoinLobbyResponse {
-                                    err: Some(crate::ClientError::LobbyNotFound {
-                                        lobby: lobby_id.to_uppercase(),
-                                    }),
-                                })
-                                .expect("Could not serialize client error to bytes");
-
-                            server.send_message(client_id, ClientChannel::Command.id(), join_response);
-                        }
-                    }
-                }
-                _ => {}
-            }
-        }
-    }
-}
-
-fn get_server(port: u16) -> RenetServer {
-    let server_addr = format!("127.0.0.1:{port}").parse().unwrap(); //TODO: Externalize these to CLAP Args
-    let socket = UdpSocket::bind(server_addr).unwrap();
-    let connection_config = server_connection_config();
-    let server_config =
-        ServerConfig::new(64, PROTOCOL_ID, server_addr, ServerAuthentication::Unsecure);
+            let bucket = pt_res
+                .rate_limiters
+                .entry(client_id)
+                .or_insert_with(|| TokenBucket::new(&settings.rate_limit, now));
+            let exempt = matches!(body, ClientToServer::Keepalive | ClientToServer::LeaveLobby);
+            if !exempt && !bucket.take(&settings.rate_limit, now) {
+                warn!("Client {client_id} is sending requests too quickly, refusing {body:?}");
+                let err = ClientError::RateLimited;
+                server.send_to_client(client_id, ServerMessage::response(request_id, ServerToClient::Error { err }));
+                continue;
+            }
+
+            let role = pt_res.lobbies.role_of(client_id);
+            if !role.allows(&body) {
+                warn!("Client {client_id} sent {body:?} which is not valid for a {role:?}");
+                let err = ClientError::Protocol(ProtocolError::InvalidForRole {
+                    role,
+                    message: format!("{body:?}"),
+                });
+                server.send_to_client(client_id, ServerMessage::response(request_id, ServerToClient::Error { err }));
+                continue;
+            }
+
+            match body {
+                ClientToServer::HostNewLobby { nat_type, mut local_addrs, info } => {
+                    local_addrs.truncate(MAX_LOCAL_ADDRS);
+                    let host = (nat_type, local_addrs, info);
+                    host_new_lobby(&mut server, pt_res, &settings, client_id, request_id, host)
+                }
+                ClientToServer::UpdateLobby { info } => {
+                    update_lobby(&mut server, pt_res, &settings, client_id, request_id, info)
+                }
+                ClientToServer::RequestSwap { lobby_id, nat_type, mut local_addrs } => {
+                    local_addrs.truncate(MAX_LOCAL_ADDRS);
+                    let joiner = (nat_type, local_addrs);
+                    request_swap(&mut server, pt_res, &settings, client_id, request_id, &lobby_id, joiner)
+                }
+                ClientToServer::ListLobbies { filter, page } => {
+                    list_lobbies(&mut server, pt_res, &settings, client_id, request_id, &filter, page)
+                }
+                ClientToServer::RequestRelay { peer } => {
+                    request_relay(&mut server, pt_res, &settings, client_id, request_id, peer, now)
+                }
+                ClientToServer::Keepalive => debug!("Keepalive from client {client_id}"),
+                ClientToServer::LeaveLobby => {
+                    info!("Client {client_id} gave up on its punch");
+                    pt_res.lobbies.remove_joiner(client_id);
+                }
+                ClientToServer::Resume { client_id: resumed, resume_token } => {
+                    resume_session(&mut server, pt_res, client_id, request_id, resumed, resume_token)
+                }
+            }
+        }
+    }
+}
+
+/// The first message from every client is its Hello, nothing else is read from it until the versions are known to match
+fn receive_hello(
+    server: &mut PTRenetServer,
+    pt_res: &mut PunchThroughServerRes,
+    settings: &ServerSettings,
+    client_id: u64,
+    now: Duration,
+) {
+    if let Some(rejected_at) = pt_res.rejected_clients.get(&client_id) {
+        while server.receive_hello(client_id).is_some() {}
+        while server.receive_from_client(client_id).is_some() {}
+
+        if now.saturating_sub(*rejected_at) >= settings.timeouts.rejected_client_grace() {
+            server.disconnect(client_id);
+        }
+        return;
+    }
+
+    let hello = match server.receive_hello(client_id) {
+        Some(Ok(hello)) => hello,
+        Some(Err(e)) => {
+            warn!("Client {client_id} did not open with a Hello: {e:#?}");
+            pt_res.rejected_clients.insert(client_id, now);
+            return;
+        }
+        None => return,
+    };
+
+    let response = HelloResponse::answer(&hello);
+    server.send_hello_response(client_id, response);
+
+    match response {
+        HelloResponse::Welcome { .. } => {
+            let capabilities = hello.capabilities.intersection(Capabilities::SUPPORTED);
+            pt_res.client_capabilities.insert(client_id, capabilities);
+            if capabilities.contains(Capabilities::PORT_PREDICTION) && !settings.nat.observers.is_empty() {
+                start_mapping_observation(server, pt_res, settings, client_id);
+            }
+            if let Some(observed) = server.client_addr(client_id) {
+                let resume_token = rand::random();
+                pt_res.observed_addrs.insert(client_id, observed);
+                pt_res.resume_tokens.insert(client_id, resume_token);
+                let address_check = settings.nat.observers.first().map(|observer| {
+                    let token = rand::random();
+                    pt_res.address_tokens.insert(token, client_id);
+                    AddressCheck { observer: observer.public_addr(), token }
+                });
+                server.send_to_client(
+                    client_id,
+                    ServerMessage::notification(ServerToClient::Session { observed, resume_token, address_check }),
+                );
+            }
+            if let Some(message) = &settings.motd {
+                server.send_to_client(
+                    client_id,
+                    ServerMessage::notification(ServerToClient::Motd { message: message.clone() }),
+                );
+            }
+        }
+        HelloResponse::Incompatible { server, client } => {
+            warn!("Client {client_id} speaks protocol version {client} but the server speaks {server}, disconnecting it");
+            pt_res.rejected_clients.insert(client_id, now);
+        }
+    }
+}
+
+/// Hands the client a token to send to every observer, so the ports its NAT uses for them can be compared
+fn start_mapping_observation(
+    server: &mut PTRenetServer,
+    pt_res: &mut PunchThroughServerRes,
+    settings: &ServerSettings,
+    client_id: u64,
+) {
+    let server_observed = match server.client_addr(client_id) {
+        Some(addr) => addr,
+        None => return,
+    };
+
+    let token = rand::random();
+    let observers: Vec<SocketAddr> = settings.nat.observers.iter().map(|bind| bind.public_addr()).collect();
+    pt_res.mapping_tokens.insert(token, client_id);
+    pt_res
+        .mappings
+        .insert(client_id, ClientMapping::new(token, server_observed, observers.len()));
+    server.send_to_client(
+        client_id,
+        ServerMessage::notification(ServerToClient::ObserveMapping { token, observers }),
+    );
+}
+
+/// Records the address each mapping probe arrived from on each observer and echoes it back to the client.
+/// Probes to the first observer are also answered from the changed port and changed IP sockets, which the client has
+/// never sent to, to find out how its NAT filters
+fn observe_mappings(observers: Res<MappingObservers>, mut server_res: ResMut<PunchThroughServerRes>) {
+    let pt_res = server_res.as_mut();
+    let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+
+    for (index, socket) in observers.sockets.iter().enumerate() {
+        loop {
+            let (len, from) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Could not read from mapping observer because {e:#?}");
+                    break;
+                }
+            };
+
+            let token = match PunchPacket::decode(&buffer[..len]) {
+                Some(PunchPacket::MappingProbe { token }) => token,
+                Some(PunchPacket::AddressKeepalive { token }) => {
+                    check_address(socket, pt_res, token, from);
+                    continue;
+                }
+                _ => continue,
+            };
+            let mapping = match pt_res.mapping_tokens.get(&token).and_then(|id| pt_res.mappings.get_mut(id)) {
+                Some(mapping) => mapping,
+                None => continue,
+            };
+
+            let known = mapping.allocation().is_some();
+            mapping.record(index, from);
+            if let (false, Some(allocation)) = (known, mapping.allocation()) {
+                info!("Client at {} has {allocation:?} port allocation", mapping.server_observed);
+            }
+
+            let echo = |path| PunchPacket::MappingEcho { token, observed: from, path }.encode();
+            let mut echoes = vec![(socket, EchoPath::Direct)];
+            if index == 0 {
+                echoes.extend(observers.changed_port.iter().map(|socket| (socket, EchoPath::ChangedPort)));
+                echoes.extend(observers.changed_ip.iter().map(|socket| (socket, EchoPath::ChangedIp)));
+            }
+            for (socket, path) in echoes {
+                if let Err(e) = socket.send_to(&echo(path), from) {
+                    warn!("Could not send {path:?} mapping echo to {from} because {e:#?}");
+                }
+            }
+        }
+    }
+}
+
+/// Notices when a host's public address moved, after a NAT reboot or a network handover, from where its address
+/// keepalives arrive. Its netcode connection is stuck on the old address, so it is told to resume its session from the
+/// new one. If its NAT maps every destination alike the lobby points joiners at the new address right away
+fn check_address(socket: &UdpSocket, pt_res: &mut PunchThroughServerRes, token: u64, from: SocketAddr) {
+    let client_id = match pt_res.address_tokens.get(&token) {
+        Some(client_id) => *client_id,
+        None => return,
+    };
+    let observed = canonical(from);
+    let previous = *pt_res.keepalive_addrs.entry(client_id).or_insert(observed);
+    if previous == observed {
+        return;
+    }
+
+    info!("Client {client_id} moved from {previous} to {observed}");
+    let server_observed = pt_res.observed_addrs.get(&client_id).copied().map(canonical);
+    if server_observed == Some(previous) {
+        pt_res.lobbies.set_host_addr(client_id, observed);
+    }
+    //Answered until the client resumed, in case one is lost
+    if let Err(e) = socket.send_to(&PunchPacket::AddressMoved { token, observed }.encode(), from) {
+        warn!("Could not tell client {client_id} it moved to {observed} because {e:#?}");
+    }
+}
+
+fn host_new_lobby(
+    server: &mut PTRenetServer,
+    pt_res: &mut PunchThroughServerRes,
+    settings: &ServerSettings,
+    client_id: u64,
+    request_id: RequestId,
+    (nat_type, local_addrs, info): (NatType, Vec<SocketAddr>, LobbyInfo),
+) {
+    if let Err(err) = check_info_size(&info, settings) {
+        server.send_to_client(client_id, ServerMessage::response(request_id, ServerToClient::Error { err }));
+        return;
+    }
+
+    let addr = match server.client_addr(client_id) {
+        Some(addr) => addr,
+        None => {
+            let err = ClientError::InternalServerError;
+            server.send_to_client(client_id, ServerMessage::response(request_id, ServerToClient::Error { err }));
+            return;
+        }
+    };
+
+    //A host replacing its own lobby doesn't add to the count
+    let replacing = pt_res.lobbies.lobby_of_host(client_id).is_some();
+    if let Some(max_lobbies) = settings.lobbies.max_lobbies {
+        if !replacing && pt_res.lobbies.len() >= max_lobbies {
+            warn!("Refusing new lobby for {client_id}, the limit of {max_lobbies} lobbies is reached");
+            let err = ClientError::LobbyLimitReached;
+            server.send_to_client(client_id, ServerMessage::response(request_id, ServerToClient::Error { err }));
+            return;
+        }
+    }
+
+    //Construct Host Lobby of LobbyId, SocketAddr
+    let id = match pt_res.lobbies.unused_code(pt_res.lobby_codes.as_ref(), &mut thread_rng()) {
+        Some(id) => id,
+        None => {
+            error!("Could not find an unused lobby code, {} lobbies are active", pt_res.lobbies.len());
+            let err = ClientError::LobbyCodesExhausted;
+            server.send_to_client(client_id, ServerMessage::response(request_id, ServerToClient::Error { err }));
+            return;
+        }
+    };
+
+    if let Some(previous) = pt_res.lobbies.insert(id.clone(), client_id, addr, nat_type, local_addrs, info) {
+        info!("Host {client_id} opened a new lobby, closing lobby {}", previous.id);
+        notify_lobby_closed(server, &previous);
+    }
+
+    server.send_to_client(
+        client_id,
+        ServerMessage::response(request_id, ServerToClient::NewLobbyResponse { lobby_id: id }),
+    );
+}
+
+/// Replaces what a host publishes about its lobby. Joiners already introduced keep the info they were given
+fn update_lobby(
+    server: &mut PTRenetServer,
+    pt_res: &mut PunchThroughServerRes,
+    settings: &ServerSettings,
+    client_id: u64,
+    request_id: RequestId,
+    info: LobbyInfo,
+) {
+    let response = match check_info_size(&info, settings) {
+        Ok(()) => match pt_res.lobbies.update_info(client_id, info) {
+            Some(lobby) => ServerToClient::LobbyUpdated { lobby_id: lobby.id.clone() },
+            None => ServerToClient::Error { err: ClientError::InternalServerError },
+        },
+        Err(err) => ServerToClient::Error { err },
+    };
+    server.send_to_client(client_id, ServerMessage::response(request_id, response));
+}
+
+/// Answers with one page of the public lobbies matching filter
+fn list_lobbies(
+    server: &mut PTRenetServer,
+    pt_res: &PunchThroughServerRes,
+    settings: &ServerSettings,
+    client_id: u64,
+    request_id: RequestId,
+    filter: &LobbyFilter,
+    page: LobbyPage,
+) {
+    let page = LobbyPage {
+        size: page.size.min(settings.lobbies.max_page_size),
+        ..page
+    };
+    let (lobbies, total) = pt_res.lobbies.list(filter, page);
+    debug!("Listing {} of {total} public lobbies for {client_id}", lobbies.len());
+    let total = total as u32;
+    server.send_to_client(
+        client_id,
+        ServerMessage::response(request_id, ServerToClient::LobbyList { lobbies, page, total }),
+    );
+}
+
+fn check_info_size(info: &LobbyInfo, settings: &ServerSettings) -> Result<(), ClientError> {
+    let max_bytes = settings.lobbies.max_info_bytes;
+    match info.text_len() <= max_bytes {
+        true => Ok(()),
+        false => Err(ClientError::LobbyInfoTooLarge { max_bytes }),
+    }
+}
+
+/// Introduces a joiner to the host of lobby_id, with the NAT type and local addresses the joiner reported
+fn request_swap(
+    server: &mut PTRenetServer,
+    pt_res: &mut PunchThroughServerRes,
+    settings: &ServerSettings,
+    client_id: u64,
+    request_id: RequestId,
+    lobby_id: &str,
+    (nat_type, local_addrs): (NatType, Vec<SocketAddr>),
+) {
+    let lobby_id = pt_res.lobby_codes.normalize(lobby_id);
+    let host = pt_res
+        .lobbies
+        .get(&lobby_id)
+        .map(|lobby| (lobby.host_id, lobby.host_addr, lobby.host_nat, lobby.host_local_addrs.clone()));
+    //A joiner asking again was already counted
+    let full = match pt_res.lobbies.get(&lobby_id) {
+        Some(lobby) => lobby.info.is_full() && !lobby.joiners.contains(&client_id),
+        None => false,
+    };
+    if full {
+        warn!("Refusing {client_id}, lobby {lobby_id} is full");
+        let err = ClientError::LobbyFull { lobby: lobby_id };
+        server.send_to_client(client_id, ServerMessage::response(request_id, ServerToClient::Error { err }));
+        return;
+    }
+    let joiner_addr = server.client_addr(client_id);
+
+    let ((host_id, host_addr, host_nat, host_local_addrs), joiner_addr) = match (host, joiner_addr) {
+        (Some(host), Some(joiner_addr)) => (host, joiner_addr),
+        (None, _) => {
+            let err = ClientError::LobbyNotFound { lobby: lobby_id };
+            server.send_to_client(client_id, ServerMessage::response(request_id, ServerToClient::Error { err }));
+            return;
+        }
+        (Some(_), None) => {
+            let err = ClientError::InternalServerError;
+            server.send_to_client(client_id, ServerMessage::response(request_id, ServerToClient::Error { err }));
+            return;
+        }
+    };
+
+    let info = match pt_res.lobbies.add_joiner(&lobby_id, client_id) {
+        Some(lobby) => lobby.info.clone(),
+        None => LobbyInfo::default(),
+    };
+    let strategy = ConnectStrategy::between(host_nat, nat_type);
+    if strategy == ConnectStrategy::Relay {
+        warn!("Host {host_id} ({host_nat:?}) and joiner {client_id} ({nat_type:?}) are unlikely to punch through");
+    }
+    let relay = settings.relay.bind.map(|bind| bind.public_addr());
+    let candidates_of = |id: u64, addr: SocketAddr, local_addrs: &[SocketAddr]| {
+        let predicted = match pt_res.mappings.get(&id) {
+            Some(mapping) => mapping.predicted_addrs(settings.nat.prediction_range),
+            None => Vec::new(),
+        };
+        //A dual stack bind sees IPv4 clients as mapped addresses, peers compare candidates against plain IPv4
+        candidate::gather(local_addrs, Some(canonical(addr)), &predicted, relay)
+    };
+    let host_candidates = candidates_of(host_id, host_addr, &host_local_addrs);
+    let joiner_candidates = candidates_of(client_id, joiner_addr, &local_addrs);
+    server.send_to_client(
+        client_id,
+        ServerMessage::response(
+            request_id,
+            ServerToClient::JoinLobbyResponse {
+                lobby_id: lobby_id.clone(),
+                strategy,
+                info,
+            },
+        ),
+    );
+
+    //Joiner checks pairs towards the host's candidates and goes along with whichever the host nominates
+    server.send_to_client(
+        client_id,
+        ServerMessage::response(
+            request_id,
+            ServerToClient::AttemptHandshakeCommand {
+                peer: host_addr,
+                role: Role::Controlled,
+                local: joiner_candidates.clone(),
+                remote: host_candidates.clone(),
+            },
+        ),
+    );
+
+    //Host is told who is joining, then checks pairs towards the joiner's candidates and nominates one
+    server.send_to_client(
+        host_id,
+        ServerMessage::notification(ServerToClient::PeerJoining {
+            lobby_id,
+            peer_id: client_id,
+        }),
+    );
+    server.send_to_client(
+        host_id,
+        ServerMessage::notification(ServerToClient::AttemptHandshakeCommand {
+            peer: joiner_addr,
+            role: Role::Controlling,
+            local: host_candidates,
+            remote: joiner_candidates,
+        }),
+    );
+}
+
+/// Carries the session of a client that rebound its socket over to its new connection. The old connection is dropped
+/// without closing the lobby, relay sessions or NAT classification, which now belong to the new one. A mapping the new
+/// connection is observing itself replaces the old one, which was seen from the address the client moved away from
+fn resume_session(
+    server: &mut PTRenetServer,
+    pt_res: &mut PunchThroughServerRes,
+    client_id: u64,
+    request_id: RequestId,
+    resumed: u64,
+    resume_token: u64,
+) {
+    let owned = resumed != client_id && pt_res.resume_tokens.get(&resumed) == Some(&resume_token);
+    let (previous, observed) = match (pt_res.observed_addrs.get(&resumed), server.client_addr(client_id)) {
+        (Some(previous), Some(observed)) if owned => (*previous, observed),
+        _ => {
+            warn!("Client {client_id} tried to resume session {resumed} which it doesn't own");
+            let err = ClientError::SessionNotFound;
+            server.send_to_client(client_id, ServerMessage::response(request_id, ServerToClient::Error { err }));
+            return;
+        }
+    };
+
+    pt_res.resume_tokens.remove(&resumed);
+    pt_res.observed_addrs.remove(&resumed);
+    pt_res.lobbies.transfer(resumed, client_id, observed);
+    pt_res.relays.transfer(resumed, client_id);
+    //Moving doesn't buy a client a fresh rate limit
+    if let Some(bucket) = pt_res.rate_limiters.remove(&resumed) {
+        pt_res.rate_limiters.insert(client_id, bucket);
+    }
+    if let Some(mapping) = pt_res.mappings.remove(&resumed) {
+        match pt_res.mappings.contains_key(&client_id) {
+            true => {
+                pt_res.mapping_tokens.remove(&mapping.token);
+            }
+            false => {
+                pt_res.mapping_tokens.insert(mapping.token, client_id);
+                pt_res.mappings.insert(client_id, mapping);
+            }
+        }
+    }
+    server.disconnect(resumed);
+    info!("Client {client_id} resumed the session of {resumed}, moving from {previous} to {observed}");
+    server.send_to_client(
+        client_id,
+        ServerMessage::response(request_id, ServerToClient::AddressChanged { previous, observed }),
+    );
+}
+
+/// Pairs a client with the counterpart at peer on the relay, or hands it the session that pair already has
+fn request_relay(
+    server: &mut PTRenetServer,
+    pt_res: &mut PunchThroughServerRes,
+    settings: &ServerSettings,
+    client_id: u64,
+    request_id: RequestId,
+    peer: SocketAddr,
+    now: Duration,
+) {
+    let relay = match settings.relay.bind {
+        Some(bind) => bind.public_addr(),
+        None => {
+            let err = ClientError::RelayUnavailable;
+            server.send_to_client(client_id, ServerMessage::response(request_id, ServerToClient::Error { err }));
+            return;
+        }
+    };
+
+    let peer_id = pt_res
+        .lobbies
+        .counterparts(client_id)
+        .into_iter()
+        .find(|id| server.client_addr(*id) == Some(peer));
+    let (peer_id, client_addr) = match (peer_id, server.client_addr(client_id)) {
+        (Some(peer_id), Some(client_addr)) => (peer_id, client_addr),
+        _ => {
+            let err = ClientError::PeerNotFound { peer };
+            server.send_to_client(client_id, ServerMessage::response(request_id, ServerToClient::Error { err }));
+            return;
+        }
+    };
+
+    //Both sides usually time out and ask, the second one gets the session the first one opened
+    if let Some(token) = pt_res.relays.between(client_id, peer_id).and_then(|session| session.token_of(client_id)) {
+        server.send_to_client(
+            client_id,
+            ServerMessage::response(request_id, ServerToClient::RelayReady { relay, token, peer }),
+        );
+        return;
+    }
+
+    if pt_res.relays.len() >= settings.relay.max_sessions {
+        warn!("Refusing relay between {client_id} and {peer_id}, {} sessions are open", pt_res.relays.len());
+        let err = ClientError::RelayUnavailable;
+        server.send_to_client(client_id, ServerMessage::response(request_id, ServerToClient::Error { err }));
+        return;
+    }
+
+    let tokens = pt_res
+        .relays
+        .open(client_id, peer_id, &settings.relay, now, &mut thread_rng())
+        .tokens;
+    info!("Relaying between {client_id} at {client_addr} and {peer_id} at {peer}");
+    server.send_to_client(
+        client_id,
+        ServerMessage::response(
+            request_id,
+            ServerToClient::RelayReady {
+                relay,
+                token: tokens[0],
+                peer,
+            },
+        ),
+    );
+    server.send_to_client(
+        peer_id,
+        ServerMessage::notification(ServerToClient::RelayReady {
+            relay,
+            token: tokens[1],
+            peer: client_addr,
+        }),
+    );
+}
+
+/// Forwards datagrams between the two sides of each relay session and closes sessions that ran out of time
+fn relay_datagrams(
+    relay: Res<RelaySocket>,
+    mut server_res: ResMut<PunchThroughServerRes>,
+    mut server: ResMut<PTRenetServer>,
+    settings: Res<ServerSettings>,
+    time: Res<Time>,
+) {
+    let pt_res = server_res.as_mut();
+    let now = time.time_since_startup();
+    let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (len, from) = match relay.socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) => {
+                warn!("Could not read from relay socket because {e:#?}");
+                break;
+            }
+        };
+
+        let (token, payload) = match PunchPacket::decode(&buffer[..len]) {
+            Some(PunchPacket::Relayed { token, payload }) => (token, payload),
+            _ => continue,
+        };
+        match pt_res.relays.forward(token, from, payload.len(), &settings.relay, now) {
+            Ok(Some((to, token))) => {
+                if let Err(e) = relay.socket.send_to(&PunchPacket::Relayed { token, payload }.encode(), to) {
+                    warn!("Could not relay datagram to {to} because {e:#?}");
+                }
+            }
+            Ok(None) => {}
+            //Over bandwidth datagrams are dropped like a congested link would, the game's own protocol copes with loss
+            Err(RelayDrop::OverBandwidth) => debug!("Relay session of {from} is over its bandwidth, dropping datagram"),
+            Err(drop) => debug!("Dropping relayed datagram from {from}: {drop:?}"),
+        }
+    }
+
+    for session in pt_res.relays.expire(now, &settings.relay) {
+        info!(
+            "Relay between {} and {} reached its time limit after {} bytes",
+            session.clients[0], session.clients[1], session.bytes_forwarded
+        );
+        notify_relay_closed(&mut server, &session);
+    }
+}
+
+/// Applies edits to the watched settings file. Settings that can't change while running are kept and reported
+fn reload_settings(
+    mut watcher: ResMut<SettingsWatcher>,
+    mut settings: ResMut<ServerSettings>,
+    server_res: Res<PunchThroughServerRes>,
+    mut server: ResMut<PTRenetServer>,
+    time: Res<Time>,
+) {
+    if !watcher.poll(time.time_since_startup()) {
+        return;
+    }
+
+    let edited = match watcher.load() {
+        Ok(edited) => edited,
+        Err(e) => {
+            error!("Ignoring changes to {}: {e}", watcher.path.display());
+            return;
+        }
+    };
+
+    let restart_required = watcher.loaded.restart_required_changes(&edited);
+    if !restart_required.is_empty() {
+        warn!(
+            "Changes to {} in {} need a restart and were not applied",
+            restart_required.join(", "),
+            watcher.path.display()
+        );
+    }
+
+    let previous_motd = settings.motd.clone();
+    settings.apply_hot_reload(&edited);
+    watcher.loaded = edited;
+    info!("Reloaded settings from {}", watcher.path.display());
+
+    if settings.motd != previous_motd {
+        if let Some(message) = &settings.motd {
+            for client_id in server_res.client_capabilities.keys() {
+                server.send_to_client(
+                    *client_id,
+                    ServerMessage::notification(ServerToClient::Motd { message: message.clone() }),
+                );
+            }
+        }
+    }
+}
+
+/// Lets every client that was waiting on a lobby know it is gone
+fn notify_lobby_closed(server: &mut PTRenetServer, lobby: &Lobby) {
+    for joiner in lobby.joiners.iter() {
+        server.send_to_client(
+            *joiner,
+            ServerMessage::notification(ServerToClient::LobbyClosed {
+                lobby_id: lobby.id.clone(),
+            }),
+        );
+    }
+}
+
+/// Lets both sides of a closed relay session know, the one that caused it may already be gone
+fn notify_relay_closed(server: &mut PTRenetServer, session: &RelaySession) {
+    for (client_id, token) in session.clients.iter().zip(session.tokens) {
+        server.send_to_client(*client_id, ServerMessage::notification(ServerToClient::RelayClosed { token }));
+    }
+}
+
+fn bind_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(addr)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Every observer or none, clients are told to probe all of them and which is which goes by position
+fn bind_observers(binds: &[ServerBind]) -> io::Result<MappingObservers> {
+    let mut sockets = Vec::new();
+    for bind in binds {
+        sockets.push(bind_socket(bind.addr)?);
+        info!("Observing NAT mappings on {} reachable at {}", bind.addr, bind.public_addr());
+    }
+    let changed_port = match binds.first() {
+        Some(first) => Some(bind_socket(SocketAddr::new(first.addr.ip(), 0))?),
+        None => None,
+    };
+    Ok(MappingObservers { sockets, changed_port, changed_ip: None })
+}
+
+fn get_server(bind: &ServerBind, plugin: &PunchThroughServerPlugin) -> io::Result<RenetServer> {
+    let socket = UdpSocket::bind(bind.addr)?;
+    let public_addr = bind.public_addr();
+    if public_addr.ip().is_unspecified() {
+        warn!("{} is not a reachable address, set a public_addr for it or clients will be refused", bind.addr);
+    }
+    let connection_config = RenetConnectionConfig {
+        heartbeat_time: plugin.settings.timeouts.heartbeat(),
+        ..server_connection_config()
+    };
+    let authentication = match &plugin.authentication {
+        ServerAuth::Unsecure => {
+            warn!("Rendezvous server is running without authentication");
+            ServerAuthentication::Unsecure
+        }
+        ServerAuth::Secure { private_key } => ServerAuthentication::Secure {
+            private_key: *private_key,
+        },
+    };
+    let server_config = ServerConfig::new(
+        plugin.settings.max_clients,
+        plugin.settings.protocol_id,
+        public_addr,
+        authentication,
+    );
     let current_time = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap();
-    let server = RenetServer::new(current_time, server_config, connection_config, socket).unwrap();
-    info!("Started Renet server on port {port}");
+    let server = RenetServer::new(current_time, server_config, connection_config, socket)?;
+    info!("Started Renet server on {} reachable at {public_addr}", bind.addr);
 
-    server
+    Ok(server)
 }
 
 pub fn server_connection_config() -> RenetConnectionConfig {
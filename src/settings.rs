@@ -0,0 +1,669 @@
+use std::{
+    collections::HashSet,
+    fmt, fs, io,
+    net::{Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use bevy::log::Level;
+use serde::Deserialize;
+
+use crate::{
+    lobby::{AlphabetCodeGenerator, LobbyCodeGenerator, WordCodeGenerator},
+    punch::MAX_DATAGRAM_SIZE,
+    server::ServerBind,
+    PROTOCOL_ID,
+};
+
+/// How often a watched settings file is checked for changes
+pub const SETTINGS_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Everything about the rendezvous server an operator can tune, usually loaded from a TOML file with ServerSettings::load.
+/// Fields marked hot reloadable take effect as soon as a watched file changes, everything else needs a restart
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    /// Addresses to listen on, as "ADDR" or "ADDR=PUBLIC_ADDR". Give an address of each family to serve IPv4 and IPv6
+    /// clients, the default listens on both loopbacks. Addresses that can't be bound are skipped with a warning
+    pub binds: Vec<ServerBind>,
+    /// Connected clients allowed on each bind
+    pub max_clients: usize,
+    /// Clients must be built with the same protocol id, see PROTOCOL_ID
+    pub protocol_id: u64,
+    /// Sent to every client once it is welcomed. Hot reloadable, connected clients get the new message
+    pub motd: Option<String>,
+    pub lobbies: LobbySettings,
+    pub nat: NatSettings,
+    pub relay: RelaySettings,
+    pub rate_limit: RateLimit,
+    pub timeouts: Timeouts,
+    pub log: LogConfig,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            binds: vec![
+                ServerBind::from(SocketAddr::from(([127, 0, 0, 1], 5000))),
+                ServerBind::from(SocketAddr::from((Ipv6Addr::LOCALHOST, 5000))),
+            ],
+            max_clients: 64,
+            protocol_id: PROTOCOL_ID,
+            motd: None,
+            lobbies: LobbySettings::default(),
+            nat: NatSettings::default(),
+            relay: RelaySettings::default(),
+            rate_limit: RateLimit::default(),
+            timeouts: Timeouts::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LobbySettings {
+    /// New lobbies are refused once this many are open. Hot reloadable, lowering it doesn't close existing lobbies
+    pub max_lobbies: Option<usize>,
+    pub codes: CodeFormat,
+    /// Most text a host may publish about its lobby, counting every field and property. Hot reloadable
+    pub max_info_bytes: usize,
+    /// Most lobbies sent in one ListLobbies page, larger requested pages are cut down to it. Hot reloadable
+    pub max_page_size: u32,
+}
+
+impl Default for LobbySettings {
+    fn default() -> Self {
+        Self {
+            max_lobbies: None,
+            codes: CodeFormat::default(),
+            max_info_bytes: 4096,
+            max_page_size: 50,
+        }
+    }
+}
+
+/// Format of the codes handed out for new lobbies
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "format", rename_all = "snake_case")]
+pub enum CodeFormat {
+    Alphabet(AlphabetCodeGenerator),
+    Words(WordCodeGenerator),
+}
+
+impl Default for CodeFormat {
+    fn default() -> Self {
+        Self::Alphabet(AlphabetCodeGenerator::default())
+    }
+}
+
+impl CodeFormat {
+    pub fn generator(&self) -> Arc<dyn LobbyCodeGenerator> {
+        match self {
+            Self::Alphabet(generator) => Arc::new(generator.clone()),
+            Self::Words(generator) => Arc::new(generator.clone()),
+        }
+    }
+}
+
+/// Port prediction for clients behind symmetric NATs
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NatSettings {
+    /// Extra UDP sockets clients send mapping probes to, so the server can see how their NAT allocates ports.
+    /// Two or more on different ports enable prediction, none turns it off
+    pub observers: Vec<ServerBind>,
+    /// Local address on a second public IP. Echoes sent from it let clients tell full cone NATs from restricted ones
+    pub alternate_ip: Option<SocketAddr>,
+    /// How many ports past the last observed one a peer probes. Hot reloadable
+    pub prediction_range: u16,
+}
+
+impl Default for NatSettings {
+    fn default() -> Self {
+        Self {
+            observers: Vec::new(),
+            alternate_ip: None,
+            prediction_range: 8,
+        }
+    }
+}
+
+/// Forwarding datagrams between peers that could not punch through to each other
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelaySettings {
+    /// UDP socket relayed datagrams go through, as "ADDR" or "ADDR=PUBLIC_ADDR". Relaying is off without one
+    pub bind: Option<ServerBind>,
+    /// Further relay requests are refused while this many sessions are open. Hot reloadable
+    pub max_sessions: usize,
+    /// Bytes forwarded per second in each session, both directions together. Hot reloadable
+    pub bytes_per_second: u32,
+    /// Bytes a quiet session may send in a row before being limited. Hot reloadable
+    pub burst_bytes: u32,
+    /// Sessions are closed this long after they open. Hot reloadable
+    pub max_session_secs: u64,
+}
+
+impl Default for RelaySettings {
+    fn default() -> Self {
+        Self {
+            bind: None,
+            max_sessions: 16,
+            bytes_per_second: 64 * 1024,
+            burst_bytes: 128 * 1024,
+            max_session_secs: 30 * 60,
+        }
+    }
+}
+
+impl RelaySettings {
+    pub fn max_session(&self) -> Duration {
+        Duration::from_secs(self.max_session_secs)
+    }
+}
+
+/// Requests each client may make, as a token bucket. Hot reloadable
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimit {
+    pub requests_per_second: f32,
+    /// Requests a quiet client can make in a row before being limited
+    pub burst: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            requests_per_second: 5.0,
+            burst: 10,
+        }
+    }
+}
+
+/// Rate limiting state for one client
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucket {
+    tokens: f32,
+    updated_at: Duration,
+}
+
+impl TokenBucket {
+    /// Starts out full
+    pub fn new(limit: &RateLimit, now: Duration) -> Self {
+        Self {
+            tokens: limit.burst as f32,
+            updated_at: now,
+        }
+    }
+
+    /// Spends a token if there is one. False means the request should be refused
+    pub fn take(&mut self, limit: &RateLimit, now: Duration) -> bool {
+        let elapsed = now.saturating_sub(self.updated_at).as_secs_f32();
+        self.tokens = (self.tokens + elapsed * limit.requests_per_second).min(limit.burst as f32);
+        self.updated_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// How long a client that failed the Hello handshake stays connected to learn why. Hot reloadable
+    pub rejected_client_grace_ms: u64,
+    /// How often an idle connection sends a packet to keep itself alive
+    pub heartbeat_ms: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            rejected_client_grace_ms: 1000,
+            heartbeat_ms: 100,
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn rejected_client_grace(&self) -> Duration {
+        Duration::from_millis(self.rejected_client_grace_ms)
+    }
+
+    pub fn heartbeat(&self) -> Duration {
+        Duration::from_millis(self.heartbeat_ms)
+    }
+}
+
+/// Read by the server binary when it sets up logging
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// One of trace, debug, info, warn or error
+    pub level: String,
+    /// Extra tracing filter directives, e.g. "bevy_punchthrough=debug"
+    pub filter: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            filter: "wgpu=error".to_string(),
+        }
+    }
+}
+
+impl LogConfig {
+    pub fn level(&self) -> Result<Level, ConfigError> {
+        self.level.parse().map_err(|_| ConfigError::InvalidLogLevel {
+            level: self.level.clone(),
+        })
+    }
+}
+
+/// A setting that would stop the server from starting or serving clients
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    NoBinds,
+    DuplicateBind { addr: SocketAddr },
+    /// Netcode only accepts clients that dialed the public address, and nobody can dial an unspecified one
+    UnreachablePublicAddr { bind: ServerBind },
+    NoClientsAllowed,
+    /// The code format can't produce any codes
+    EmptyLobbyCode,
+    NoRequestsAllowed,
+    InvalidLogLevel { level: String },
+    /// The relay is enabled but its limits don't let a full datagram through
+    NoRelayBandwidth,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoBinds => write!(f, "at least one bind address is required"),
+            Self::DuplicateBind { addr } => write!(f, "{addr} is bound more than once"),
+            Self::UnreachablePublicAddr { bind } => {
+                write!(f, "{bind} needs a public address clients can reach, e.g. {}=<public ip>:{}", bind.addr, bind.addr.port())
+            }
+            Self::NoClientsAllowed => write!(f, "max clients must be at least 1"),
+            Self::EmptyLobbyCode => write!(f, "lobby codes would be empty, check the code length and alphabet"),
+            Self::NoRequestsAllowed => write!(f, "the rate limit must allow at least one request"),
+            Self::InvalidLogLevel { level } => write!(f, "{level} is not a log level"),
+            Self::NoRelayBandwidth => write!(
+                f,
+                "the relay must allow at least {MAX_DATAGRAM_SIZE} bytes per second and burst, and one session"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Invalid(ConfigError),
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "could not read settings file: {e}"),
+            Self::Parse(e) => write!(f, "could not parse settings file: {e}"),
+            Self::Invalid(e) => write!(f, "invalid settings: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+impl ServerSettings {
+    /// Reads and validates a TOML settings file
+    pub fn load(path: &Path) -> Result<Self, SettingsError> {
+        let text = fs::read_to_string(path).map_err(SettingsError::Io)?;
+        Self::from_toml(&text)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, SettingsError> {
+        let settings: Self = toml::from_str(text).map_err(SettingsError::Parse)?;
+        settings.validate().map_err(SettingsError::Invalid)?;
+        Ok(settings)
+    }
+
+    /// Checks the settings without binding any sockets
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.binds.is_empty() {
+            return Err(ConfigError::NoBinds);
+        }
+
+        let mut addrs = HashSet::new();
+        for bind in self.binds.iter().chain(self.nat.observers.iter()).chain(self.relay.bind.iter()) {
+            if !addrs.insert(bind.addr) {
+                return Err(ConfigError::DuplicateBind { addr: bind.addr });
+            }
+            if bind.public_addr().ip().is_unspecified() {
+                return Err(ConfigError::UnreachablePublicAddr { bind: *bind });
+            }
+        }
+
+        if self.max_clients == 0 {
+            return Err(ConfigError::NoClientsAllowed);
+        }
+
+        let no_codes = match &self.lobbies.codes {
+            CodeFormat::Alphabet(generator) => generator.length == 0 || generator.charset().is_empty(),
+            CodeFormat::Words(generator) => generator.adjectives.is_empty() || generator.nouns.is_empty(),
+        };
+        if no_codes {
+            return Err(ConfigError::EmptyLobbyCode);
+        }
+
+        if self.rate_limit.burst == 0 || self.rate_limit.requests_per_second <= 0.0 {
+            return Err(ConfigError::NoRequestsAllowed);
+        }
+
+        if self.relay.bind.is_some() {
+            let datagram = MAX_DATAGRAM_SIZE as u32;
+            let relay = &self.relay;
+            if relay.bytes_per_second < datagram || relay.burst_bytes < datagram || relay.max_sessions == 0 {
+                return Err(ConfigError::NoRelayBandwidth);
+            }
+        }
+
+        self.log.level()?;
+        Ok(())
+    }
+
+    /// Settings that differ between self and other but only take effect on a restart
+    pub fn restart_required_changes(&self, other: &Self) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.binds != other.binds {
+            changed.push("binds");
+        }
+        if self.max_clients != other.max_clients {
+            changed.push("max_clients");
+        }
+        if self.protocol_id != other.protocol_id {
+            changed.push("protocol_id");
+        }
+        if self.nat.observers != other.nat.observers {
+            changed.push("nat.observers");
+        }
+        if self.nat.alternate_ip != other.nat.alternate_ip {
+            changed.push("nat.alternate_ip");
+        }
+        if self.relay.bind != other.relay.bind {
+            changed.push("relay.bind");
+        }
+        if self.lobbies.codes != other.lobbies.codes {
+            changed.push("lobbies.codes");
+        }
+        if self.timeouts.heartbeat_ms != other.timeouts.heartbeat_ms {
+            changed.push("timeouts.heartbeat_ms");
+        }
+        if self.log != other.log {
+            changed.push("log");
+        }
+        changed
+    }
+
+    /// Copies the hot reloadable settings from other, leaving the rest as they are
+    pub fn apply_hot_reload(&mut self, other: &Self) {
+        self.motd = other.motd.clone();
+        self.lobbies.max_lobbies = other.lobbies.max_lobbies;
+        self.lobbies.max_info_bytes = other.lobbies.max_info_bytes;
+        self.lobbies.max_page_size = other.lobbies.max_page_size;
+        self.nat.prediction_range = other.nat.prediction_range;
+        self.rate_limit = other.rate_limit;
+        self.relay = RelaySettings {
+            bind: self.relay.bind,
+            ..other.relay.clone()
+        };
+        self.timeouts.rejected_client_grace_ms = other.timeouts.rejected_client_grace_ms;
+    }
+}
+
+/// Settings given outside the settings file, such as on the command line. They take precedence over the file and are
+/// applied again every time it is reloaded
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SettingsOverrides {
+    /// Replaces the file's binds unless empty
+    pub binds: Vec<ServerBind>,
+    pub relay: Option<ServerBind>,
+    pub max_clients: Option<usize>,
+    pub protocol_id: Option<u64>,
+    pub log_level: Option<String>,
+    pub log_filter: Option<String>,
+    pub word_codes: bool,
+    pub code_length: Option<usize>,
+    pub code_alphabet: Option<String>,
+    pub exclude_ambiguous: bool,
+    pub rejected_client_grace_ms: Option<u64>,
+    pub heartbeat_ms: Option<u64>,
+}
+
+impl SettingsOverrides {
+    /// Overrides the settings with every value that was given
+    pub fn apply(&self, settings: &mut ServerSettings) {
+        if !self.binds.is_empty() {
+            settings.binds = self.binds.clone();
+        }
+        if let Some(relay) = self.relay {
+            settings.relay.bind = Some(relay);
+        }
+        if let Some(max_clients) = self.max_clients {
+            settings.max_clients = max_clients;
+        }
+        if let Some(protocol_id) = self.protocol_id {
+            settings.protocol_id = protocol_id;
+        }
+        if let Some(level) = &self.log_level {
+            settings.log.level = level.clone();
+        }
+        if let Some(filter) = &self.log_filter {
+            settings.log.filter = filter.clone();
+        }
+
+        if self.word_codes {
+            settings.lobbies.codes = CodeFormat::Words(WordCodeGenerator::default());
+        } else if self.code_length.is_some() || self.code_alphabet.is_some() || self.exclude_ambiguous {
+            let mut generator = match &settings.lobbies.codes {
+                CodeFormat::Alphabet(generator) => generator.clone(),
+                CodeFormat::Words(_) => AlphabetCodeGenerator::default(),
+            };
+            if let Some(length) = self.code_length {
+                generator.length = length;
+            }
+            if let Some(alphabet) = &self.code_alphabet {
+                generator.alphabet = alphabet.clone();
+            }
+            generator.exclude_ambiguous |= self.exclude_ambiguous;
+            settings.lobbies.codes = CodeFormat::Alphabet(generator);
+        }
+
+        if let Some(grace) = self.rejected_client_grace_ms {
+            settings.timeouts.rejected_client_grace_ms = grace;
+        }
+        if let Some(heartbeat) = self.heartbeat_ms {
+            settings.timeouts.heartbeat_ms = heartbeat;
+        }
+    }
+}
+
+/// Tracks the settings file a running server was started from, see ServerSettings::load
+pub struct SettingsWatcher {
+    pub path: PathBuf,
+    /// Applied over the file every time it is read
+    pub overrides: SettingsOverrides,
+    /// What the file held with the overrides applied when it was last read, so only edits to it are reported
+    pub loaded: ServerSettings,
+    pub modified: Option<SystemTime>,
+    pub next_poll: Duration,
+}
+
+impl SettingsWatcher {
+    pub fn new(path: PathBuf, overrides: SettingsOverrides, loaded: ServerSettings) -> Self {
+        let modified = modified_time(&path);
+        Self {
+            path,
+            overrides,
+            loaded,
+            modified,
+            next_poll: Duration::ZERO,
+        }
+    }
+
+    /// Reads the file again, with the overrides applied over it
+    pub fn load(&self) -> Result<ServerSettings, SettingsError> {
+        let mut settings = ServerSettings::load(&self.path)?;
+        self.overrides.apply(&mut settings);
+        Ok(settings)
+    }
+
+    /// Whether the file was written since it was last read
+    pub fn poll(&mut self, now: Duration) -> bool {
+        if now < self.next_poll {
+            return false;
+        }
+        self.next_poll = now + SETTINGS_POLL_INTERVAL;
+
+        let modified = modified_time(&self.path);
+        if modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+        true
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_load_from_toml() {
+        let settings = ServerSettings::from_toml(
+            r#"
+            binds = ["0.0.0.0:5000=203.0.113.7:5000", "[::1]:5001"]
+            motd = "Welcome"
+
+            [lobbies]
+            max_lobbies = 100
+
+            [lobbies.codes]
+            format = "alphabet"
+            length = 6
+            exclude_ambiguous = true
+
+            [rate_limit]
+            requests_per_second = 2.0
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(settings.binds.len(), 2);
+        assert_eq!(settings.motd.as_deref(), Some("Welcome"));
+        assert_eq!(settings.lobbies.max_lobbies, Some(100));
+        assert!(matches!(&settings.lobbies.codes, CodeFormat::Alphabet(codes) if codes.length == 6 && codes.exclude_ambiguous));
+        assert_eq!(settings.rate_limit.burst, RateLimit::default().burst);
+        assert_eq!(settings.max_clients, ServerSettings::default().max_clients);
+
+        assert!(matches!(ServerSettings::from_toml("max_clients = 0"), Err(SettingsError::Invalid(_))));
+        assert!(matches!(
+            ServerSettings::from_toml("[lobbies.codes]\nformat = \"alphabet\"\nalphabet = \"01\"\nexclude_ambiguous = true"),
+            Err(SettingsError::Invalid(ConfigError::EmptyLobbyCode))
+        ));
+        assert!(matches!(
+            ServerSettings::from_toml("[relay]\nbind = \"127.0.0.1:5002\"\nburst_bytes = 100"),
+            Err(SettingsError::Invalid(ConfigError::NoRelayBandwidth))
+        ));
+        assert!(matches!(ServerSettings::from_toml("port = 5000"), Err(SettingsError::Parse(_))));
+        assert!(matches!(
+            ServerSettings::from_toml("[lobbies.codes]\nformat = \"alphabet\"\nlenght = 6"),
+            Err(SettingsError::Parse(_))
+        ));
+        assert!(matches!(
+            ServerSettings::from_toml("[lobbies.codes]\nformat = \"words\"\nseparater = \"_\""),
+            Err(SettingsError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn only_safe_settings_hot_reload() {
+        let mut running = ServerSettings::default();
+        let mut edited = ServerSettings {
+            max_clients: 10,
+            motd: Some("Maintenance at noon".to_string()),
+            ..Default::default()
+        };
+        edited.lobbies.max_lobbies = Some(5);
+        edited.lobbies.max_page_size = 10;
+        edited.binds = vec!["127.0.0.1:6000".parse().unwrap()];
+
+        assert_eq!(running.restart_required_changes(&edited), ["binds", "max_clients"]);
+
+        running.apply_hot_reload(&edited);
+        assert_eq!(running.motd, edited.motd);
+        assert_eq!(running.lobbies.max_lobbies, Some(5));
+        assert_eq!(running.lobbies.max_page_size, 10);
+        assert_eq!(running.binds, ServerSettings::default().binds);
+        assert_eq!(running.max_clients, ServerSettings::default().max_clients);
+    }
+
+    #[test]
+    fn overrides_survive_a_reload() {
+        let path = std::env::temp_dir().join(format!("punchthrough_settings_{}.toml", std::process::id()));
+        let file = |motd: &str| {
+            format!("max_clients = 16\nmotd = \"{motd}\"\n[timeouts]\nrejected_client_grace_ms = 100\n")
+        };
+        fs::write(&path, file("Before")).unwrap();
+        let overrides = SettingsOverrides {
+            max_clients: Some(32),
+            rejected_client_grace_ms: Some(2000),
+            ..Default::default()
+        };
+        let mut running = ServerSettings::load(&path).unwrap();
+        overrides.apply(&mut running);
+        let watcher = SettingsWatcher::new(path.clone(), overrides, running.clone());
+
+        fs::write(&path, file("After")).unwrap();
+        let edited = watcher.load();
+        fs::remove_file(&path).unwrap();
+        let edited = edited.unwrap();
+
+        //Neither a restart only override nor a hot reloadable one falls back to the file
+        assert!(watcher.loaded.restart_required_changes(&edited).is_empty());
+        running.apply_hot_reload(&edited);
+        assert_eq!(running.motd.as_deref(), Some("After"));
+        assert_eq!(running.timeouts.rejected_client_grace_ms, 2000);
+        assert_eq!(running.max_clients, 32);
+    }
+
+    #[test]
+    fn token_bucket_refills_over_time() {
+        let limit = RateLimit {
+            requests_per_second: 2.0,
+            burst: 2,
+        };
+        let mut bucket = TokenBucket::new(&limit, Duration::ZERO);
+
+        assert!(bucket.take(&limit, Duration::ZERO));
+        assert!(bucket.take(&limit, Duration::ZERO));
+        assert!(!bucket.take(&limit, Duration::ZERO));
+        assert!(bucket.take(&limit, Duration::from_millis(500)));
+        assert!(!bucket.take(&limit, Duration::from_millis(500)));
+    }
+}
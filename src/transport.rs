@@ -0,0 +1,118 @@
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use bevy::prelude::*;
+use bevy_renet::renet::{ConnectToken, DisconnectionReason, RemoteConnection, RenetConnectionConfig, RenetError};
+use renetcode::{NetcodeClient, NetcodeError};
+
+use crate::nat::sendable;
+
+/// The rendezvous client's connection to the server, sharing the punch socket. Unlike RenetClient it never reads the
+/// socket itself, client::receive_punch_packets drains every datagram and hands the server's to process_packet
+pub struct RendezvousTransport {
+    netcode: NetcodeClient,
+    connection: RemoteConnection,
+    socket: UdpSocket,
+}
+
+impl RendezvousTransport {
+    pub fn new(
+        current_time: Duration,
+        socket: UdpSocket,
+        connect_token: ConnectToken,
+        config: RenetConnectionConfig,
+    ) -> Self {
+        Self {
+            netcode: NetcodeClient::new(current_time, connect_token),
+            connection: RemoteConnection::new(config),
+            socket,
+        }
+    }
+
+    pub fn client_id(&self) -> u64 {
+        self.netcode.client_id()
+    }
+
+    pub fn server_addr(&self) -> SocketAddr {
+        self.netcode.server_addr()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.netcode.is_connected()
+    }
+
+    pub fn disconnected(&self) -> Option<DisconnectionReason> {
+        match self.netcode.is_disconnected() {
+            true => Some(DisconnectionReason::DisconnectedByServer),
+            false => self.connection.disconnected(),
+        }
+    }
+
+    /// A datagram that came from the rendezvous server
+    pub fn process_packet(&mut self, packet: &mut [u8]) {
+        if let Some(payload) = self.netcode.process_packet(packet) {
+            if let Err(e) = self.connection.process_packet(payload) {
+                warn!("Could not process packet from the rendezvous server: {e}");
+            }
+        }
+    }
+
+    pub fn send_message(&mut self, channel_id: u8, message: Vec<u8>) {
+        self.connection.send_message(channel_id, message);
+    }
+
+    pub fn receive_message(&mut self, channel_id: u8) -> Option<Vec<u8>> {
+        self.connection.receive_message(channel_id)
+    }
+
+    /// Advances the connection's timers and sends netcode's own packets, such as connection requests and keepalives
+    pub fn update(&mut self, duration: Duration) -> Result<(), RenetError> {
+        self.connection.advance_time(duration);
+        if self.disconnected().is_some() {
+            return Ok(());
+        }
+
+        self.connection.update()?;
+        if let Some((packet, addr)) = self.netcode.update(duration) {
+            send_to(&self.socket, packet, addr)?;
+        }
+        Ok(())
+    }
+
+    pub fn send_packets(&mut self) -> Result<(), RenetError> {
+        if !self.netcode.is_connected() {
+            return Ok(());
+        }
+
+        for packet in self.connection.get_packets_to_send()? {
+            let (addr, payload) = self.netcode.generate_payload_packet(&packet).map_err(netcode_error)?;
+            send_to(&self.socket, payload, addr)?;
+        }
+        Ok(())
+    }
+
+    /// Tells the server we are leaving. Only this handle to the socket closes, the punch socket stays bound
+    pub fn disconnect(&mut self) {
+        match self.netcode.disconnect() {
+            Ok((addr, packet)) => {
+                if let Err(e) = send_to(&self.socket, packet, addr) {
+                    warn!("Could not tell the rendezvous server we are leaving: {e}");
+                }
+            }
+            Err(e) => debug!("Already disconnected from the rendezvous server: {e}"),
+        }
+    }
+}
+
+/// The connect token names the server as it is configured, a socket bound to [::] can only reach it IPv4 mapped
+fn send_to(socket: &UdpSocket, bytes: &[u8], to: SocketAddr) -> io::Result<usize> {
+    let local = socket.local_addr()?;
+    socket.send_to(bytes, sendable(to, local))
+}
+
+fn netcode_error(e: NetcodeError) -> io::Error {
+    io::Error::other(e)
+}
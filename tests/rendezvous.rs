@@ -0,0 +1,542 @@
+use std::{
+    net::{SocketAddr, UdpSocket},
+    thread,
+    time::Duration,
+};
+
+use bevy::{app::App, ecs::event::{Events, ManualEventReader}, MinimalPlugins};
+use bevy_punchthrough::{
+    auth::ClientAuth,
+    bevy_renet::renet::{RenetClient, RenetServer},
+    client::{
+        rebind, LobbyListReceived, PunchthroughClientPlugin, PunchthroughClientRes, PunchthroughEvent,
+        PunchthroughState, RequestLobbyList, RequestSwap,
+    },
+    punch::{KeepaliveSchedule, PunchSchedule},
+    server::{PunchThroughServerPlugin, PunchThroughServerRes, ServerBind},
+    handoff::{hand_off, PeerHandoff},
+    lobby::{LobbyFilter, LobbyInfo},
+    nat::{IpFamily, NatType, PortAllocation},
+    settings::{NatSettings, RelaySettings, ServerSettings},
+    ClientChannel, PROTOCOL_ID,
+};
+
+const SERVER_PORT: u16 = 5111;
+const RELAY_SERVER_PORT: u16 = 5121;
+const IPV6_SERVER_PORT: u16 = 5131;
+const HANDOFF_SERVER_PORT: u16 = 5141;
+const KEEPALIVE_SERVER_PORT: u16 = 5151;
+const REBIND_SERVER_PORT: u16 = 5161;
+const LISTING_SERVER_PORT: u16 = 5171;
+const MOVED_SERVER_PORT: u16 = 5181;
+
+struct TestClient {
+    app: App,
+    reader: ManualEventReader<PunchthroughEvent>,
+    received: Vec<PunchthroughEvent>,
+    list_reader: ManualEventReader<LobbyListReceived>,
+    lobby_lists: Vec<LobbyListReceived>,
+}
+
+/// How a test client's plugin is set up, anything not overridden keeps the plugin's defaults
+#[derive(Clone, Copy, Default)]
+struct TestClientConfig {
+    punch_schedule: PunchSchedule,
+    keepalive: KeepaliveSchedule,
+}
+
+impl TestClientConfig {
+    fn punch_schedule(mut self, punch_schedule: PunchSchedule) -> Self {
+        self.punch_schedule = punch_schedule;
+        self
+    }
+
+    fn keepalive(mut self, keepalive: KeepaliveSchedule) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    fn build(self, server_addr: SocketAddr) -> TestClient {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins).add_plugin(PunchthroughClientPlugin {
+            //Same loopback family as the server, renet only talks to it over the family it is bound to
+            local_socket: SocketAddr::new(server_addr.ip(), 0),
+            punchthrough_server: server_addr,
+            authentication: ClientAuth::Unsecure,
+            protocol_id: PROTOCOL_ID,
+            punch_schedule: self.punch_schedule,
+            keepalive: self.keepalive,
+        });
+
+        TestClient {
+            app,
+            reader: ManualEventReader::default(),
+            received: Vec::new(),
+            list_reader: ManualEventReader::default(),
+            lobby_lists: Vec::new(),
+        }
+    }
+}
+
+impl TestClient {
+    fn new(server_addr: SocketAddr) -> Self {
+        TestClientConfig::default().build(server_addr)
+    }
+
+    fn update(&mut self) {
+        self.app.update();
+        let events = self.app.world.resource::<Events<PunchthroughEvent>>();
+        for event in self.reader.iter(events) {
+            self.received.push(event.clone());
+        }
+        let lists = self.app.world.resource::<Events<LobbyListReceived>>();
+        for list in self.list_reader.iter(lists) {
+            self.lobby_lists.push(list.clone());
+        }
+    }
+
+    fn request(&mut self, request: RequestSwap) {
+        self.app.world.resource_mut::<Events<RequestSwap>>().send(request);
+    }
+
+    fn res(&self) -> &PunchthroughClientRes {
+        self.app.world.resource::<PunchthroughClientRes>()
+    }
+
+    fn state(&self) -> &PunchthroughState {
+        self.app.world.resource::<PunchthroughState>()
+    }
+}
+
+/// Steps the server and both clients until `done` holds, panicking if that takes too long
+fn run_until(server: &mut App, clients: &mut [&mut TestClient], mut done: impl FnMut(&[&mut TestClient]) -> bool) {
+    for _ in 0..500 {
+        server.update();
+        for client in clients.iter_mut() {
+            client.update();
+        }
+
+        if done(clients) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    panic!("Timed out waiting on the rendezvous flow");
+}
+
+/// A rendezvous server bound to `server_addr` on top of `settings`
+fn rendezvous_server(server_addr: SocketAddr, settings: ServerSettings) -> App {
+    let mut server = App::new();
+    server.add_plugins(MinimalPlugins).add_plugin(PunchThroughServerPlugin {
+        settings: ServerSettings {
+            binds: vec![ServerBind::from(server_addr)],
+            ..settings
+        },
+        ..Default::default()
+    });
+    server
+}
+
+/// Connects both clients and has the host open a lobby, returning its code
+fn open_lobby(server: &mut App, host: &mut TestClient, joiner: &mut TestClient) -> String {
+    run_until(server, &mut [&mut *host, &mut *joiner], |clients| {
+        clients.iter().all(|client| *client.state() == PunchthroughState::Idle)
+    });
+    host.request(RequestSwap::HostLobby { info: LobbyInfo::default() });
+    run_until(server, &mut [&mut *host, &mut *joiner], |clients| {
+        matches!(clients[0].state(), PunchthroughState::Hosting { .. })
+    });
+    match host.state() {
+        PunchthroughState::Hosting { lobby } => lobby.clone(),
+        state => panic!("Host is {state:?}"),
+    }
+}
+
+#[test]
+fn host_and_joiner_punch_to_each_other() {
+    let server_addr: SocketAddr = format!("127.0.0.1:{SERVER_PORT}").parse().unwrap();
+    let mut server = rendezvous_server(
+        server_addr,
+        ServerSettings {
+            nat: NatSettings {
+                observers: vec![
+                    ServerBind::from(SocketAddr::from(([127, 0, 0, 1], SERVER_PORT + 1))),
+                    ServerBind::from(SocketAddr::from(([127, 0, 0, 1], SERVER_PORT + 2))),
+                ],
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+
+    let mut host = TestClient::new(server_addr);
+    let mut joiner = TestClient::new(server_addr);
+
+    run_until(&mut server, &mut [&mut host, &mut joiner], |clients| {
+        clients.iter().all(|client| *client.state() == PunchthroughState::Idle)
+    });
+
+    let info = LobbyInfo {
+        name: "Integration".to_string(),
+        map: "de_loopback".to_string(),
+        players: 1,
+        max_players: 2,
+        ..Default::default()
+    };
+    host.request(RequestSwap::HostLobby { info: info.clone() });
+    run_until(&mut server, &mut [&mut host, &mut joiner], |clients| {
+        clients[0].received.iter().any(|event| matches!(event, PunchthroughEvent::HostSuccess { .. }))
+    });
+    let lobby = host
+        .received
+        .iter()
+        .find_map(|event| match event {
+            PunchthroughEvent::HostSuccess { lobby } => Some(lobby.clone()),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(*host.state(), PunchthroughState::Hosting { lobby: lobby.clone() });
+
+    joiner.request(RequestSwap::JoinLobby { lobby: lobby.to_lowercase() });
+    run_until(&mut server, &mut [&mut host, &mut joiner], |clients| {
+        clients.iter().all(|client| {
+            client.received.iter().any(|event| matches!(event, PunchthroughEvent::Success { .. }))
+        })
+    });
+
+    let host_addr = host.res().punch_socket.local_addr().unwrap();
+    let joiner_addr = joiner.res().punch_socket.local_addr().unwrap();
+    let joiner_id = joiner.res().client.client_id();
+
+    assert!(host.received.iter().any(|event| matches!(
+        event,
+        PunchthroughEvent::PeerJoining { lobby: joined, peer_id } if *joined == lobby && *peer_id == joiner_id
+    )));
+    assert!(host.received.iter().any(|event| matches!(
+        event,
+        PunchthroughEvent::Success { target_sock, local_sock, family: IpFamily::V4 }
+            if *target_sock == joiner_addr && *local_sock == host_addr
+    )));
+    assert!(joiner.received.iter().any(|event| matches!(
+        event,
+        PunchthroughEvent::Success { target_sock, local_sock, family: IpFamily::V4 }
+            if *target_sock == host_addr && *local_sock == joiner_addr
+    )));
+    assert_eq!(*host.state(), PunchthroughState::Connected { peer: joiner_addr });
+    //The joiner is counted into the info it is handed
+    assert!(joiner.received.iter().any(|event| matches!(
+        event,
+        PunchthroughEvent::JoinSuccess { lobby: joined, info: joined_info }
+            if *joined == lobby && joined_info.map == info.map && joined_info.players == 2
+    )));
+
+    //Loopback doesn't translate anything, so every observer sees the same port
+    let server_res = server.world.resource::<PunchThroughServerRes>();
+    let joiner_mapping = &server_res.mappings[&joiner_id];
+    assert_eq!(joiner_mapping.allocation(), Some(PortAllocation::EndpointIndependent));
+    assert!(joiner_mapping.predicted_addrs(8).is_empty());
+    assert_eq!(*joiner.state(), PunchthroughState::Connected { peer: host_addr });
+
+    //Nothing sits between loopback sockets, both ends see their own address echoed back
+    for client in [&host, &joiner] {
+        assert_eq!(*client.app.world.resource::<NatType>(), NatType::Open);
+        assert!(client.received.iter().any(|event| matches!(
+            event,
+            PunchthroughEvent::NatClassified { nat_type: NatType::Open }
+        )));
+    }
+}
+
+#[test]
+fn peers_fall_back_to_the_relay() {
+    let server_addr: SocketAddr = format!("127.0.0.1:{RELAY_SERVER_PORT}").parse().unwrap();
+    let relay_addr = SocketAddr::from(([127, 0, 0, 1], RELAY_SERVER_PORT + 1));
+    let mut server = rendezvous_server(
+        server_addr,
+        ServerSettings {
+            relay: RelaySettings {
+                bind: Some(ServerBind::from(relay_addr)),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+
+    //Loopback always punches through, so both sides give up before their first probe
+    let schedule = PunchSchedule {
+        deadline: Duration::ZERO,
+        ..Default::default()
+    };
+    let config = TestClientConfig::default().punch_schedule(schedule);
+    let mut host = config.build(server_addr);
+    let mut joiner = config.build(server_addr);
+
+    let lobby = open_lobby(&mut server, &mut host, &mut joiner);
+
+    joiner.request(RequestSwap::JoinLobby { lobby });
+    run_until(&mut server, &mut [&mut host, &mut joiner], |clients| {
+        clients.iter().all(|client| matches!(client.state(), PunchthroughState::Relayed { .. }))
+    });
+
+    let host_addr = host.res().punch_socket.local_addr().unwrap();
+    let joiner_addr = joiner.res().punch_socket.local_addr().unwrap();
+    assert_eq!(*host.state(), PunchthroughState::Relayed { peer: joiner_addr });
+    assert!(joiner.received.iter().any(|event| matches!(
+        event,
+        PunchthroughEvent::Relayed { relay, peer } if *relay == relay_addr && *peer == host_addr
+    )));
+
+    //The joiner may not have registered with the relay yet when the host first sends
+    let mut received = Vec::new();
+    run_until(&mut server, &mut [&mut host, &mut joiner], |clients| {
+        clients[0].res().send_relayed(b"ping").unwrap();
+        received.extend(clients[1].res().relay_inbox.iter().cloned());
+        !received.is_empty()
+    });
+    assert_eq!(received[0], b"ping");
+}
+
+#[test]
+fn peers_punch_over_ipv6_loopback() {
+    let server_addr: SocketAddr = format!("[::1]:{IPV6_SERVER_PORT}").parse().unwrap();
+    let mut server = rendezvous_server(server_addr, ServerSettings::default());
+
+    let mut host = TestClient::new(server_addr);
+    let mut joiner = TestClient::new(server_addr);
+    let lobby = open_lobby(&mut server, &mut host, &mut joiner);
+
+    joiner.request(RequestSwap::JoinLobby { lobby });
+    run_until(&mut server, &mut [&mut host, &mut joiner], |clients| {
+        clients.iter().all(|client| matches!(client.state(), PunchthroughState::Connected { .. }))
+    });
+
+    let host_addr = host.res().punch_socket.local_addr().unwrap();
+    let joiner_addr = joiner.res().punch_socket.local_addr().unwrap();
+    assert!(host_addr.is_ipv6() && joiner_addr.is_ipv6());
+    assert!(host.received.iter().any(|event| matches!(
+        event,
+        PunchthroughEvent::Success { target_sock, family: IpFamily::V6, .. } if *target_sock == joiner_addr
+    )));
+    assert!(joiner.received.iter().any(|event| matches!(
+        event,
+        PunchthroughEvent::Success { target_sock, family: IpFamily::V6, .. } if *target_sock == host_addr
+    )));
+}
+
+#[test]
+fn punched_socket_is_handed_to_the_game() {
+    let server_addr: SocketAddr = format!("127.0.0.1:{HANDOFF_SERVER_PORT}").parse().unwrap();
+    let mut server = rendezvous_server(server_addr, ServerSettings::default());
+
+    let mut host = TestClient::new(server_addr);
+    let mut joiner = TestClient::new(server_addr);
+    let lobby = open_lobby(&mut server, &mut host, &mut joiner);
+    joiner.request(RequestSwap::JoinLobby { lobby });
+    run_until(&mut server, &mut [&mut host, &mut joiner], |clients| {
+        clients.iter().all(|client| matches!(client.state(), PunchthroughState::Connected { .. }))
+    });
+
+    let host_addr = host.res().punch_socket.local_addr().unwrap();
+    let joiner_addr = joiner.res().punch_socket.local_addr().unwrap();
+    assert_eq!(hand_off(&mut host.app.world, &PeerHandoff::default()).unwrap(), joiner_addr);
+    assert_eq!(hand_off(&mut joiner.app.world, &PeerHandoff::default()).unwrap(), host_addr);
+    assert!(hand_off(&mut joiner.app.world, &PeerHandoff::default()).is_err());
+
+    //The game's renet connection runs over the same ports the punch opened
+    let mut game_server = host.app.world.remove_resource::<RenetServer>().unwrap();
+    let mut game_client = joiner.app.world.remove_resource::<RenetClient>().unwrap();
+    let tick = Duration::from_millis(10);
+    for _ in 0..500 {
+        game_client.update(tick).unwrap();
+        game_server.update(tick).unwrap();
+        if game_client.is_connected() {
+            game_client.send_message(ClientChannel::Command.id(), b"hello host".to_vec());
+        }
+        game_client.send_packets().unwrap();
+        game_server.send_packets().unwrap();
+
+        let client_id = game_client.client_id();
+        if let Some(message) = game_server.receive_message(client_id, ClientChannel::Command.id()) {
+            assert_eq!(message, b"hello host");
+            return;
+        }
+        thread::sleep(tick);
+    }
+    panic!("The joiner never reached the host over the handed off socket");
+}
+
+#[test]
+fn punched_peers_exchange_keepalives() {
+    let server_addr: SocketAddr = format!("127.0.0.1:{KEEPALIVE_SERVER_PORT}").parse().unwrap();
+    let mut server = rendezvous_server(server_addr, ServerSettings::default());
+
+    let keepalive = KeepaliveSchedule {
+        server_interval: Duration::from_millis(50),
+        peer_interval: Duration::from_millis(50),
+        ..Default::default()
+    };
+    let config = TestClientConfig::default().keepalive(keepalive);
+    let mut host = config.build(server_addr);
+    let mut joiner = config.build(server_addr);
+    let lobby = open_lobby(&mut server, &mut host, &mut joiner);
+    joiner.request(RequestSwap::JoinLobby { lobby });
+
+    run_until(&mut server, &mut [&mut host, &mut joiner], |clients| {
+        clients.iter().all(|client| matches!(client.res().peer_link, Some(link) if link.keepalives_received >= 3))
+    });
+    let joiner_addr = joiner.res().punch_socket.local_addr().unwrap();
+    let link = host.res().peer_link.unwrap();
+    assert_eq!(link.peer, joiner_addr);
+    assert!(link.keepalives_sent >= 3 && !link.unresponsive);
+    assert!(host.res().server_keepalive_at.is_some());
+
+    //Keepalives are never answered, so the host's lobby stays open and nothing failed
+    assert!(!host.received.iter().any(|event| matches!(
+        event,
+        PunchthroughEvent::Failed { .. } | PunchthroughEvent::PeerUnresponsive { .. }
+    )));
+}
+
+#[test]
+fn rebound_host_keeps_its_lobby() {
+    let server_addr: SocketAddr = format!("127.0.0.1:{REBIND_SERVER_PORT}").parse().unwrap();
+    let mut server = rendezvous_server(server_addr, ServerSettings::default());
+
+    let mut host = TestClient::new(server_addr);
+    let mut joiner = TestClient::new(server_addr);
+    let lobby = open_lobby(&mut server, &mut host, &mut joiner);
+    run_until(&mut server, &mut [&mut host, &mut joiner], |clients| clients[0].res().resume_token.is_some());
+    let old_addr = host.res().punch_socket.local_addr().unwrap();
+    assert_eq!(host.res().public_addr, Some(old_addr));
+
+    //A new port on loopback stands in for the NAT handing out a new mapping
+    rebind(&mut host.app.world, "127.0.0.1:0".parse().unwrap()).unwrap();
+    assert_eq!(*host.state(), PunchthroughState::ConnectingToRendezvous);
+    let new_addr = host.res().punch_socket.local_addr().unwrap();
+    assert_ne!(new_addr, old_addr);
+    run_until(&mut server, &mut [&mut host, &mut joiner], |clients| {
+        clients[0].received.iter().any(|event| matches!(event, PunchthroughEvent::AddressChanged { .. }))
+    });
+
+    assert!(host.received.iter().any(|event| matches!(
+        event,
+        PunchthroughEvent::AddressChanged { previous, current } if *previous == old_addr && *current == new_addr
+    )));
+    assert_eq!(host.res().public_addr, Some(new_addr));
+    assert_eq!(*host.state(), PunchthroughState::Hosting { lobby: lobby.clone() });
+    let server_res = server.world.resource::<PunchThroughServerRes>();
+    assert_eq!(server_res.lobbies.get(&lobby).unwrap().host_addr, new_addr);
+
+    //Joiners are pointed at the new address
+    joiner.request(RequestSwap::JoinLobby { lobby });
+    run_until(&mut server, &mut [&mut host, &mut joiner], |clients| {
+        clients.iter().all(|client| matches!(client.state(), PunchthroughState::Connected { .. }))
+    });
+    assert_eq!(*joiner.state(), PunchthroughState::Connected { peer: new_addr });
+}
+
+#[test]
+fn moved_host_is_noticed_by_the_server() {
+    let server_addr: SocketAddr = format!("127.0.0.1:{MOVED_SERVER_PORT}").parse().unwrap();
+    let mut server = rendezvous_server(
+        server_addr,
+        ServerSettings {
+            nat: NatSettings {
+                observers: vec![ServerBind::from(SocketAddr::from(([127, 0, 0, 1], MOVED_SERVER_PORT + 1)))],
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+
+    let keepalive = KeepaliveSchedule {
+        server_interval: Duration::from_millis(50),
+        ..Default::default()
+    };
+    let config = TestClientConfig::default().keepalive(keepalive);
+    let mut host = config.build(server_addr);
+    let mut joiner = config.build(server_addr);
+    let lobby = open_lobby(&mut server, &mut host, &mut joiner);
+    let old_id = host.res().client.client_id();
+    //The first address keepalive tells the server where the host was
+    let hosted_at = host.res().server_keepalive_at;
+    run_until(&mut server, &mut [&mut host, &mut joiner], |clients| {
+        clients[0].res().server_keepalive_at != hosted_at
+    });
+    let old_addr = host.res().punch_socket.local_addr().unwrap();
+
+    //The NAT starts handing out a new port without the game knowing. The rendezvous connection is stuck on the old one
+    let moved = UdpSocket::bind("127.0.0.1:0").unwrap();
+    moved.set_nonblocking(true).unwrap();
+    let new_addr = moved.local_addr().unwrap();
+    host.app.world.resource_mut::<PunchthroughClientRes>().punch_socket = moved;
+    run_until(&mut server, &mut [&mut host, &mut joiner], |clients| {
+        clients[0].received.iter().any(|event| matches!(event, PunchthroughEvent::AddressChanged { .. }))
+    });
+
+    assert!(host.received.iter().any(|event| matches!(
+        event,
+        PunchthroughEvent::AddressChanged { previous, current } if *previous == old_addr && *current == new_addr
+    )));
+    assert_ne!(host.res().client.client_id(), old_id);
+    assert_eq!(*host.state(), PunchthroughState::Hosting { lobby: lobby.clone() });
+    let server_res = server.world.resource::<PunchThroughServerRes>();
+    assert_eq!(server_res.lobbies.get(&lobby).unwrap().host_addr, new_addr);
+
+    joiner.request(RequestSwap::JoinLobby { lobby });
+    run_until(&mut server, &mut [&mut host, &mut joiner], |clients| {
+        clients.iter().all(|client| matches!(client.state(), PunchthroughState::Connected { .. }))
+    });
+    assert_eq!(*joiner.state(), PunchthroughState::Connected { peer: new_addr });
+}
+
+#[test]
+fn public_lobbies_are_listed() {
+    let server_addr: SocketAddr = format!("127.0.0.1:{LISTING_SERVER_PORT}").parse().unwrap();
+    let mut server = rendezvous_server(server_addr, ServerSettings::default());
+
+    let mut public = TestClient::new(server_addr);
+    let mut private = TestClient::new(server_addr);
+    let mut browser = TestClient::new(server_addr);
+    let info = LobbyInfo {
+        name: "Open to all".to_string(),
+        game_mode: "ctf".to_string(),
+        players: 1,
+        max_players: 8,
+        public: true,
+        ..Default::default()
+    };
+    public.request(RequestSwap::HostLobby { info: info.clone() });
+    private.request(RequestSwap::HostLobby { info: LobbyInfo { public: false, ..info.clone() } });
+    run_until(&mut server, &mut [&mut public, &mut private, &mut browser], |clients| {
+        clients[..2].iter().all(|client| {
+            client.received.iter().any(|event| matches!(event, PunchthroughEvent::HostSuccess { .. }))
+        })
+    });
+    let lobby = public
+        .received
+        .iter()
+        .find_map(|event| match event {
+            PunchthroughEvent::HostSuccess { lobby } => Some(lobby.clone()),
+            _ => None,
+        })
+        .unwrap();
+
+    let mut filter = LobbyFilter::default();
+    filter.equals.insert("game_mode".to_string(), "ctf".to_string());
+    browser.app.world.resource_mut::<Events<RequestLobbyList>>().send(RequestLobbyList {
+        filter,
+        ..Default::default()
+    });
+    run_until(&mut server, &mut [&mut public, &mut private, &mut browser], |clients| {
+        !clients[2].lobby_lists.is_empty()
+    });
+
+    //Only the public lobby shows up, the private one is still joinable by its code alone
+    let list = &browser.lobby_lists[0];
+    assert_eq!(list.total, 1);
+    assert_eq!(list.lobbies.len(), 1);
+    assert_eq!(list.lobbies[0].lobby_id, lobby);
+    assert_eq!(list.lobbies[0].info, info);
+}